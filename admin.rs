@@ -0,0 +1,369 @@
+use crate::auth::{self, CredentialStore};
+use crate::config::ServerConfig;
+use crate::deadline::Stalled;
+use crate::frame;
+use crate::logging;
+use crate::message::{
+    admin_request, admin_response, auth_request, AdminAck, AdminRequest, AdminResponse, AuthRequest, ConnectionList,
+    ConnectionSummary, ErrorCode, ErrorResponse, LogLevel, ServerStats,
+};
+use crate::policy::MessageKind;
+use crate::server::Shared;
+use log::LevelFilter;
+use prost::Message;
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+#[cfg(unix)]
+use std::{
+    os::unix::{fs::FileTypeExt, net::UnixListener},
+    path::PathBuf,
+};
+
+// How long an idle admin connection blocks before checking whether the server is still running.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Where the admin listener accepts connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf), // A stale socket file at this path is replaced.
+}
+
+// Produces the configuration applied by ReloadConfig, e.g. by reading files from disk again.
+pub trait ConfigSource: Send + Sync + fmt::Debug {
+    fn load(&self) -> io::Result<ServerConfig>;
+}
+
+// Settings of the admin listener. They are read once when the server is created.
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    pub address: AdminAddress,
+    pub credentials: Arc<dyn CredentialStore>, // Admin tokens; client credentials are not accepted here.
+    pub reload: Option<Arc<dyn ConfigSource>>, // Without a source, ReloadConfig is refused.
+}
+
+impl AdminConfig {
+    pub fn new(address: AdminAddress, credentials: Arc<dyn CredentialStore>) -> Self {
+        AdminConfig {
+            address,
+            credentials,
+            reload: None,
+        }
+    }
+
+    pub fn with_reload(mut self, source: Arc<dyn ConfigSource>) -> Self {
+        self.reload = Some(source);
+        self
+    }
+}
+
+pub fn log_level(filter: LevelFilter) -> LogLevel {
+    match filter {
+        LevelFilter::Off => LogLevel::Off,
+        LevelFilter::Error => LogLevel::Error,
+        LevelFilter::Warn => LogLevel::Warn,
+        LevelFilter::Info => LogLevel::Info,
+        LevelFilter::Debug => LogLevel::Debug,
+        LevelFilter::Trace => LogLevel::Trace,
+    }
+}
+
+pub fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn error(code: ErrorCode, message: impl Into<String>) -> admin_response::Result {
+    admin_response::Result::Error(ErrorResponse {
+        code: code as i32,
+        message: message.into(),
+    })
+}
+
+fn ack(message: impl Into<String>) -> admin_response::Result {
+    admin_response::Result::Ack(AdminAck { message: message.into() })
+}
+
+// Reads one admin frame. Poll timeouts before its first byte are passed on so the caller can check for a
+// shutdown; once the frame has started they are retried, so the bytes already read are never lost.
+struct FrameReader<'a, S> {
+    stream: &'a mut S,
+    running: &'a AtomicBool,
+    frame_timeout: Option<Duration>,
+    started: Option<Instant>,
+}
+
+impl<S: Read> Read for FrameReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Ok(n) => {
+                    if n > 0 {
+                        self.started.get_or_insert_with(Instant::now);
+                    }
+                    return Ok(n);
+                }
+                Err(ref e) if self.started.is_some() && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let started = self.started.unwrap();
+                    if !self.running.load(Ordering::SeqCst) {
+                        return Err(io::Error::new(ErrorKind::ConnectionAborted, "server stopped mid-frame"));
+                    }
+                    if let Some(limit) = self.frame_timeout.filter(|&limit| started.elapsed() >= limit) {
+                        return Err(Stalled::FrameTimeout { limit }.into());
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+// The admin listener of a server and the settings it was created with.
+pub(crate) struct AdminServer {
+    listener: Listener,
+    config: AdminConfig,
+}
+
+impl AdminServer {
+    pub(crate) fn bind(config: AdminConfig) -> io::Result<Self> {
+        let listener = match config.address {
+            AdminAddress::Tcp(ref addr) => Listener::Tcp(TcpListener::bind(addr)?),
+            #[cfg(unix)]
+            AdminAddress::Unix(ref path) => {
+                // Only ever remove a socket, never a regular file that happens to be in the way.
+                if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                Listener::Unix(UnixListener::bind(path)?, path.clone())
+            }
+        };
+        match listener {
+            Listener::Tcp(ref listener) => listener.set_nonblocking(true)?,
+            #[cfg(unix)]
+            Listener::Unix(ref listener, _) => listener.set_nonblocking(true)?,
+        }
+        Ok(AdminServer { listener, config })
+    }
+
+    // Accepts admin connections until the server stops.
+    pub(crate) fn run(self: Arc<Self>, shared: Arc<Shared>) {
+        while shared.is_running.load(Ordering::SeqCst) {
+            let accepted = match self.listener {
+                Listener::Tcp(ref listener) => listener.accept().and_then(|(stream, addr)| {
+                    info!("Admin connection from {}", addr);
+                    stream.set_nonblocking(false)?;
+                    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                    self.spawn(stream, &shared);
+                    Ok(())
+                }),
+                #[cfg(unix)]
+                Listener::Unix(ref listener, _) => listener.accept().and_then(|(stream, _)| {
+                    info!("Admin connection on the Unix socket");
+                    stream.set_nonblocking(false)?;
+                    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                    self.spawn(stream, &shared);
+                    Ok(())
+                }),
+            };
+            match accepted {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(10)),
+                Err(e) => error!("Error accepting admin connection: {}", e),
+            }
+        }
+    }
+
+    fn spawn<S: Read + Write + Send + 'static>(self: &Arc<Self>, stream: S, shared: &Arc<Shared>) {
+        let (admin, shared) = (self.clone(), shared.clone());
+        thread::spawn(move || admin.serve(stream, &shared));
+    }
+
+    // Answers requests on one admin connection until it closes, fails to authenticate or the server stops.
+    // The read timeout set on accept lets the loop notice a shutdown between requests.
+    fn serve<S: Read + Write>(&self, mut stream: S, shared: &Shared) {
+        let codec = frame::Codec::new(shared.config().max_frame_size);
+        while shared.is_running.load(Ordering::SeqCst) {
+            let mut reader = FrameReader {
+                stream: &mut stream,
+                running: &shared.is_running,
+                frame_timeout: shared.config().frame_timeout,
+                started: None,
+            };
+            let request = match codec.read(&mut reader) {
+                Ok(Some(payload)) => match AdminRequest::decode(&payload[..]) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("Failed to decode admin request: {}", e);
+                        continue;
+                    }
+                },
+                Ok(None) => return,
+                Err(ref e) if Stalled::from_io(e).is_some() => {
+                    warn!("Closing admin connection: {}", e);
+                    return;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    warn!("Closing admin connection: {}", e);
+                    return;
+                }
+            };
+
+            let (result, shutdown, rejected) = match self.authenticate(&request) {
+                Ok(()) => {
+                    let (result, shutdown) = self.execute(request, shared);
+                    (result, shutdown, false)
+                }
+                Err(e) => (admin_response::Result::Error(e), None, true),
+            };
+            let response = AdminResponse { result: Some(result) };
+            if let Err(e) = codec.write(&mut stream, &response.encode_to_vec()) {
+                error!("Failed to send admin response: {}", e);
+                return;
+            }
+            if rejected {
+                return;  // Like data clients, a failed attempt costs the connection.
+            }
+            if let Some(grace) = shutdown {
+                shared.shutdown(grace);  // After the acknowledgement, which would otherwise wait out the grace period.
+                return;
+            }
+        }
+    }
+
+    fn authenticate(&self, request: &AdminRequest) -> Result<(), ErrorResponse> {
+        let credentials = AuthRequest {
+            identity: request.identity.clone(),
+            credential: Some(auth_request::Credential::Token(request.token.clone())),
+        };
+        auth::verify(self.config.credentials.as_ref(), &credentials, None).inspect_err(|e| {
+            warn!("Rejecting admin credentials for '{}': {}", request.identity, e.message);
+        })
+    }
+
+    // Runs one command. Also returns the grace period of a requested shutdown, which is left to the caller.
+    fn execute(&self, request: AdminRequest, shared: &Shared) -> (admin_response::Result, Option<Duration>) {
+        let Some(command) = request.command else {
+            return (error(ErrorCode::InvalidArgument, "admin request contained no command"), None);
+        };
+        info!("Admin '{}' requested {:?}", request.identity, command);
+        let result = match command {
+            admin_request::Command::ListConnections(_) => {
+                let connections = shared
+                    .registry
+                    .list()
+                    .into_iter()
+                    .map(|connection| ConnectionSummary {
+                        id: connection.id,
+                        peer: connection.peer.to_string(),
+                        tls: connection.tls,
+                        connected_at_ms: millis(connection.connected_at),
+                        last_activity_ms: millis(connection.last_activity),
+                        bytes_in: connection.bytes_in,
+                        bytes_out: connection.bytes_out,
+                        messages_in: connection.messages_in,
+                        messages_out: connection.messages_out,
+                        identity: connection.identity.unwrap_or_default(),
+                    })
+                    .collect();
+                admin_response::Result::Connections(ConnectionList { connections })
+            }
+            admin_request::Command::KickConnection(kick) => {
+                if shared.registry.disconnect(kick.id) {
+                    ack(format!("connection {} closed", kick.id))
+                } else {
+                    error(ErrorCode::NotFound, format!("no live connection {}", kick.id))
+                }
+            }
+            admin_request::Command::GetStats(_) => {
+                let mut disabled: Vec<String> =
+                    shared.disabled.read().unwrap().iter().map(|kind| kind.name().to_string()).collect();
+                disabled.sort();
+                admin_response::Result::Stats(ServerStats {
+                    uptime_ms: shared.started.elapsed().as_millis() as u64,
+                    open_connections: shared.connections.open_connections() as u64,
+                    accepted_connections: shared.accepted.load(Ordering::SeqCst),
+                    rejected_connections: shared.connections.rejected_connections(),
+                    corrupted_frames: shared.corrupted_frames.load(Ordering::SeqCst),
+                    log_level: log_level(logging::level()) as i32,
+                    disabled_handlers: disabled,
+                })
+            }
+            admin_request::Command::SetLogLevel(set) => {
+                logging::set_level(level_filter(set.level()));
+                ack(format!("log level set to {:?}", set.level()))
+            }
+            admin_request::Command::SetHandlerEnabled(set) => match MessageKind::parse(&set.handler) {
+                Some(kind) => {
+                    let mut disabled = shared.disabled.write().unwrap();
+                    if set.enabled {
+                        disabled.remove(&kind);
+                    } else {
+                        disabled.insert(kind);
+                    }
+                    ack(format!("{} {}", kind.name(), if set.enabled { "enabled" } else { "disabled" }))
+                }
+                None => error(ErrorCode::InvalidArgument, format!("unknown handler `{}`", set.handler)),
+            },
+            admin_request::Command::Shutdown(shutdown) => {
+                let grace = Duration::from_millis(shutdown.grace_ms);
+                return (ack(format!("shutting down within {:?}", grace)), Some(grace));
+            }
+            admin_request::Command::ReloadConfig(_) => match self.config.reload {
+                Some(ref source) => match source.load() {
+                    // The limiters and the log sampler are built once, so changes to their settings would be lost.
+                    Ok(config) => match shared.config().fixed_changes(&config) {
+                        changed if !changed.is_empty() => error(
+                            ErrorCode::InvalidArgument,
+                            format!("{} cannot be changed without a restart", changed.join(", ")),
+                        ),
+                        _ => {
+                            *shared.config.write().unwrap() = Arc::new(config);
+                            info!("Configuration reloaded.");
+                            ack("configuration reloaded")
+                        }
+                    },
+                    Err(e) => error(ErrorCode::InvalidArgument, format!("failed to reload configuration: {}", e)),
+                },
+                None => error(ErrorCode::InvalidArgument, "no configuration source to reload from"),
+            },
+        };
+        (result, None)
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, ref path) = self.listener {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
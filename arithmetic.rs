@@ -0,0 +1,176 @@
+use crate::message::{
+    ArithmeticOperation, Decimal, DecimalArithmeticRequest, DecimalArithmeticResponse, ErrorCode, ErrorResponse,
+    FloatArithmeticRequest, FloatArithmeticResponse, FloatClass, NonFinitePolicy, RoundingMode,
+};
+
+// Largest decimal scale accepted, which keeps intermediate values inside an i128.
+pub const MAX_DECIMAL_SCALE: u32 = 18;
+
+fn error(code: ErrorCode, message: impl Into<String>) -> ErrorResponse {
+    ErrorResponse {
+        code: code as i32,
+        message: message.into(),
+    }
+}
+
+fn operation(value: i32) -> Result<ArithmeticOperation, ErrorResponse> {
+    ArithmeticOperation::try_from(value)
+        .map_err(|_| error(ErrorCode::InvalidArgument, format!("unknown arithmetic operation {}", value)))
+}
+
+fn classify(value: f64) -> FloatClass {
+    if value.is_nan() {
+        FloatClass::Nan
+    } else if value == f64::INFINITY {
+        FloatClass::PositiveInfinity
+    } else if value == f64::NEG_INFINITY {
+        FloatClass::NegativeInfinity
+    } else {
+        FloatClass::Finite
+    }
+}
+
+pub fn float(request: &FloatArithmeticRequest) -> Result<FloatArithmeticResponse, ErrorResponse> {
+    let (a, b) = (request.a, request.b);
+    let op = operation(request.operation)?;
+    let reject = request.non_finite() == NonFinitePolicy::Reject;
+
+    if reject && !(a.is_finite() && b.is_finite()) {
+        return Err(error(ErrorCode::InvalidArgument, "operands must be finite"));
+    }
+    if reject && op == ArithmeticOperation::Divide && b == 0.0 {
+        return Err(error(ErrorCode::DivisionByZero, "division by zero"));
+    }
+
+    // The rounding error of each operation is recovered exactly (TwoSum / FMA) to detect inexact results.
+    let (result, rounding_error) = match op {
+        ArithmeticOperation::Add | ArithmeticOperation::Subtract => {
+            let b = if op == ArithmeticOperation::Add { b } else { -b };
+            let sum = a + b;
+            let b_virtual = sum - a;
+            (sum, (a - (sum - b_virtual)) + (b - b_virtual))
+        }
+        ArithmeticOperation::Multiply => {
+            let product = a * b;
+            (product, a.mul_add(b, -product))
+        }
+        ArithmeticOperation::Divide => {
+            let quotient = a / b;
+            (quotient, (-quotient).mul_add(b, a))
+        }
+    };
+
+    let overflowed = a.is_finite() && b.is_finite() && !result.is_finite() && !(op == ArithmeticOperation::Divide && b == 0.0);
+    if reject && !result.is_finite() {
+        return Err(error(ErrorCode::Overflow, "result is not finite"));
+    }
+
+    Ok(FloatArithmeticResponse {
+        result,
+        class: classify(result) as i32,
+        inexact: overflowed || (result.is_finite() && rounding_error != 0.0),
+    })
+}
+
+fn pow10(exponent: u32) -> i128 {
+    10i128.pow(exponent)
+}
+
+fn overflow() -> ErrorResponse {
+    error(ErrorCode::Overflow, "decimal result out of range")
+}
+
+// Divides `num` by `den` (den > 0), rounding the quotient with `mode`. Returns the quotient and whether it is inexact.
+fn round_div(num: i128, den: i128, mode: RoundingMode) -> (i128, bool) {
+    let quotient = num / den;
+    let remainder = num % den;
+    if remainder == 0 {
+        return (quotient, false);
+    }
+
+    let negative = num < 0;
+    let twice = remainder.unsigned_abs() * 2;
+    let half = twice.cmp(&den.unsigned_abs());
+    let away = match mode {
+        RoundingMode::Up => true,
+        RoundingMode::Down => false,
+        RoundingMode::Ceiling => !negative,
+        RoundingMode::Floor => negative,
+        RoundingMode::HalfUp => half.is_ge(),
+        RoundingMode::HalfDown => half.is_gt(),
+        RoundingMode::HalfEven => half.is_gt() || (half.is_eq() && quotient % 2 != 0),
+    };
+
+    let rounded = if !away {
+        quotient
+    } else if negative {
+        quotient - 1
+    } else {
+        quotient + 1
+    };
+    (rounded, true)
+}
+
+fn check_scale(name: &str, scale: u32) -> Result<(), ErrorResponse> {
+    if scale > MAX_DECIMAL_SCALE {
+        return Err(error(
+            ErrorCode::InvalidArgument,
+            format!("{} scale {} exceeds the maximum of {}", name, scale, MAX_DECIMAL_SCALE),
+        ));
+    }
+    Ok(())
+}
+
+pub fn decimal(request: &DecimalArithmeticRequest) -> Result<DecimalArithmeticResponse, ErrorResponse> {
+    let op = operation(request.operation)?;
+    let rounding = RoundingMode::try_from(request.rounding)
+        .map_err(|_| error(ErrorCode::InvalidArgument, format!("unknown rounding mode {}", request.rounding)))?;
+    let a = request.a.unwrap_or_default();
+    let b = request.b.unwrap_or_default();
+    check_scale("a", a.scale)?;
+    check_scale("b", b.scale)?;
+    check_scale("result", request.result_scale)?;
+
+    let (au, bu) = (a.units as i128, b.units as i128);
+
+    // Express the exact result as the fraction num / den.
+    let (num, mut den) = match op {
+        ArithmeticOperation::Add | ArithmeticOperation::Subtract => {
+            let scale = a.scale.max(b.scale);
+            let left = au * pow10(scale - a.scale);
+            let right = bu * pow10(scale - b.scale);
+            let num = if op == ArithmeticOperation::Add { left + right } else { left - right };
+            (num, pow10(scale))
+        }
+        ArithmeticOperation::Multiply => (au * bu, pow10(a.scale + b.scale)),
+        ArithmeticOperation::Divide => {
+            if bu == 0 {
+                return Err(error(ErrorCode::DivisionByZero, "division by zero"));
+            }
+            let num = au * pow10(b.scale);
+            let den = bu * pow10(a.scale);
+            if den < 0 {
+                (-num, -den)
+            } else {
+                (num, den)
+            }
+        }
+    };
+
+    // Scale the fraction to `result_scale` digits, cancelling powers of ten first to stay in range.
+    let mut shift = request.result_scale;
+    while shift > 0 && den % 10 == 0 {
+        den /= 10;
+        shift -= 1;
+    }
+    let num = num.checked_mul(pow10(shift)).ok_or_else(overflow)?;
+    let (units, inexact) = round_div(num, den, rounding);
+
+    Ok(DecimalArithmeticResponse {
+        result: Some(Decimal {
+            units: i64::try_from(units).map_err(|_| overflow())?,
+            scale: request.result_scale,
+        }),
+        inexact,
+    })
+}
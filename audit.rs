@@ -0,0 +1,85 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// A request the server refused on policy grounds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub time: SystemTime,
+    pub peer: Option<SocketAddr>,
+    pub identity: Option<String>, // `None` for connections that did not authenticate.
+    pub message: &'static str,    // `MessageKind::name` of the request.
+    pub reason: String,
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(f, "{}.{:03} denied", time.as_secs(), time.subsec_millis())?;
+        match self.peer {
+            Some(peer) => write!(f, " peer={}", peer)?,
+            None => write!(f, " peer=-")?,
+        }
+        write!(
+            f,
+            " identity={} message={} reason={:?}",
+            self.identity.as_deref().unwrap_or("-"),
+            self.message,
+            self.reason
+        )
+    }
+}
+
+// Destination of audit events.
+pub trait AuditSink: Send + Sync + fmt::Debug {
+    fn record(&self, event: &AuditEvent);
+}
+
+// Keeps events in memory, e.g. for inspection by an embedding application.
+#[derive(Debug, Default)]
+pub struct MemoryAudit {
+    events: Mutex<Vec<AuditEvent>>,
+}
+
+impl MemoryAudit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> Vec<AuditEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl AuditSink for MemoryAudit {
+    fn record(&self, event: &AuditEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+// Appends one line per event to a file.
+#[derive(Debug)]
+pub struct FileAudit {
+    file: Mutex<File>,
+}
+
+impl FileAudit {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileAudit { file: Mutex::new(file) })
+    }
+}
+
+impl AuditSink for FileAudit {
+    fn record(&self, event: &AuditEvent) {
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", event) {
+            tracing::error!("Failed to write audit event: {}", e);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::message::{auth_request, AuthRequest, ErrorCode, ErrorResponse};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::Path,
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Length of the nonce sent in an AuthChallenge.
+pub const NONCE_LEN: usize = 32;
+
+// A shared secret, accepted both as a bearer token and as the HMAC key for challenges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credential {
+    pub secret: String,
+    pub expires_at: Option<SystemTime>, // `None` never expires.
+}
+
+impl Credential {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Credential {
+            secret: secret.into(),
+            expires_at: None,
+        }
+    }
+
+    pub fn expiring_at(mut self, expires_at: SystemTime) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+}
+
+// Where the server looks up the credential of an identity.
+pub trait CredentialStore: Send + Sync + fmt::Debug {
+    fn lookup(&self, identity: &str) -> Option<Credential>;
+}
+
+// Credentials held in memory; entries can be added and revoked while the server runs.
+#[derive(Debug, Default)]
+pub struct MemoryCredentials {
+    credentials: RwLock<HashMap<String, Credential>>,
+}
+
+impl MemoryCredentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, identity: impl Into<String>, credential: Credential) {
+        self.credentials.write().unwrap().insert(identity.into(), credential);
+    }
+
+    pub fn remove(&self, identity: &str) -> Option<Credential> {
+        self.credentials.write().unwrap().remove(identity)
+    }
+}
+
+impl CredentialStore for MemoryCredentials {
+    fn lookup(&self, identity: &str) -> Option<Credential> {
+        self.credentials.read().unwrap().get(identity).cloned()
+    }
+}
+
+// Credentials read once from a text file with one `identity secret [expires-at]` entry per line,
+// where `expires-at` is in seconds since the Unix epoch. Blank lines and `#` comments are ignored.
+#[derive(Debug)]
+pub struct FileCredentials {
+    credentials: HashMap<String, Credential>,
+}
+
+impl FileCredentials {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let invalid = |line: usize, message: &str| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), line, message))
+        };
+
+        let mut credentials = HashMap::new();
+        for (index, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let mut credential = match fields[..] {
+                [_, secret] | [_, secret, _] => Credential::new(secret),
+                _ => return Err(invalid(index + 1, "expected `identity secret [expires-at]`")),
+            };
+            if let Some(expires_at) = fields.get(2) {
+                let seconds = expires_at.parse().map_err(|_| invalid(index + 1, "invalid expiry timestamp"))?;
+                credential = credential.expiring_at(UNIX_EPOCH + Duration::from_secs(seconds));
+            }
+            if credentials.insert(fields[0].to_string(), credential).is_some() {
+                return Err(invalid(index + 1, "duplicate identity"));
+            }
+        }
+        Ok(FileCredentials { credentials })
+    }
+}
+
+impl CredentialStore for FileCredentials {
+    fn lookup(&self, identity: &str) -> Option<Credential> {
+        self.credentials.get(identity).cloned()
+    }
+}
+
+fn mac(secret: &str) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length")
+}
+
+// Answer to an AuthChallenge: HMAC-SHA256 of `nonce` keyed with `secret`.
+pub fn sign(secret: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut mac = mac(secret);
+    mac.update(nonce);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Generates a fresh challenge nonce from the system's secure random source.
+pub fn challenge() -> Vec<u8> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    rustls::crypto::ring::default_provider()
+        .secure_random
+        .fill(&mut nonce)
+        .expect("secure random source unavailable");
+    nonce
+}
+
+// Compares without exiting early, so response times do not reveal how much of a token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn error(code: ErrorCode, message: impl Into<String>) -> ErrorResponse {
+    ErrorResponse {
+        code: code as i32,
+        message: message.into(),
+    }
+}
+
+// Checks a token or challenge signature against `store`. `challenge` is the nonce last issued on
+// the connection, if any. Unknown identities and wrong secrets are reported identically.
+pub fn verify(store: &dyn CredentialStore, request: &AuthRequest, challenge: Option<&[u8]>) -> Result<(), ErrorResponse> {
+    let rejected = || error(ErrorCode::Unauthenticated, "invalid credentials");
+    let credential = store.lookup(&request.identity);
+
+    let valid = match (&request.credential, challenge) {
+        (Some(auth_request::Credential::Token(token)), _) => {
+            credential.as_ref().is_some_and(|credential| constant_time_eq(token.as_bytes(), credential.secret.as_bytes()))
+        }
+        (Some(auth_request::Credential::Signature(signature)), Some(nonce)) => credential.as_ref().is_some_and(|credential| {
+            let mut mac = mac(&credential.secret);
+            mac.update(nonce);
+            mac.verify_slice(signature).is_ok()
+        }),
+        (Some(auth_request::Credential::Signature(_)), None) => {
+            return Err(error(ErrorCode::Unauthenticated, "no challenge was issued on this connection"))
+        }
+        (Some(auth_request::Credential::Challenge(_)), _) | (None, _) => {
+            return Err(error(ErrorCode::Unauthenticated, "missing credentials"))
+        }
+    };
+
+    match credential {
+        Some(credential) if valid => match credential.expires_at {
+            Some(expires_at) if expires_at <= SystemTime::now() => {
+                Err(error(ErrorCode::CredentialsExpired, "credentials have expired"))
+            }
+            _ => Ok(()),
+        },
+        _ => Err(rejected()),
+    }
+}
@@ -1,7 +1,38 @@
 use std::error::Error;
 
+// Bytes fields, given as `(path, field)`, carried as base64 strings in the JSON encoding.
+const BYTES_FIELDS: [(&str, &str); 5] = [
+    (".messages.BinaryEchoRequest", "payload"),
+    (".messages.BinaryEchoResponse", "payload"),
+    (".messages.BinaryEchoResponse", "digest"),
+    (".messages.AuthRequest", "signature"),
+    (".messages.AuthChallenge", "nonce"),
+];
+
+// Double fields, given as `(path, field)`, that may hold NaN or infinities. Oneof variants are
+// given with their oneof in the path.
+const FLOAT_FIELDS: [(&str, &str); 4] = [
+    (".messages.Number.value", "float"),
+    (".messages.FloatArithmeticRequest", "a"),
+    (".messages.FloatArithmeticRequest", "b"),
+    (".messages.FloatArithmeticResponse", "result"),
+];
+
 fn main() -> Result<(), Box<dyn Error>> {
-    prost_build::compile_protos(&["proto/messages.proto"], &["proto/"])?;
+    let mut config = prost_build::Config::new();
+    // The JSON encoding (see `encoding.rs`): fields keep their proto names and may be left out,
+    // oneofs are objects with a single snake_case key, and enums are their numeric values.
+    config
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .message_attribute(".", "#[serde(default)]")
+        .enum_attribute(".", "#[serde(rename_all = \"snake_case\")]");
+    for (message, field) in BYTES_FIELDS {
+        config.field_attribute(format!("{}.{}", message, field), "#[serde(with = \"crate::encoding::base64\")]");
+    }
+    for (message, field) in FLOAT_FIELDS {
+        config.field_attribute(format!("{}.{}", message, field), "#[serde(with = \"crate::encoding::float\")]");
+    }
+    config.compile_protos(&["proto/messages.proto"], &["proto/"])?;
 
     Ok(())
 }
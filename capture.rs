@@ -0,0 +1,252 @@
+use crate::encoding::Encoding;
+use crate::frame;
+use crate::logging;
+use crate::message::{server_message, Capability, ClientMessage, CompressionAlgorithm, ServerMessage};
+use crate::registry::ConnectionId;
+use serde_json::{json, Value};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Largest response payload the replay accepts.
+const MAX_RESPONSE_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,  // A ClientMessage read from the client.
+    Outbound, // A ServerMessage sent to the client.
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+        }
+    }
+}
+
+// One captured frame. `payload` is the message as the connection encoded it, after decompression and
+// without the checksum, so a capture replays whatever the frame settings of the connection were.
+// Inbound credentials are redacted before recording; see `redact`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub time: SystemTime,
+    pub connection: ConnectionId,
+    pub direction: Direction,
+    pub payload: Vec<u8>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn invalid(line: usize, reason: impl fmt::Display) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("capture line {}: {}", line, reason))
+}
+
+impl Record {
+    // The record as one line of JSON, e.g.
+    // `{"time_ms":1700000000123,"connection":3,"direction":"in","payload":"0a0568656c6c6f"}`.
+    pub fn to_json(&self) -> String {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        json!({
+            "time_ms": time.as_millis() as u64,
+            "connection": self.connection,
+            "direction": self.direction.name(),
+            "payload": hex(&self.payload),
+        })
+        .to_string()
+    }
+
+    // Parses a line written by `to_json`. `time_ms` may be left out of hand-written fixtures.
+    pub fn from_json(line: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        let time_ms = value["time_ms"].as_u64().unwrap_or(0);
+        let connection = value["connection"].as_u64().ok_or("missing `connection`")?;
+        let direction = match value["direction"].as_str() {
+            Some("in") => Direction::Inbound,
+            Some("out") => Direction::Outbound,
+            _ => return Err("`direction` must be \"in\" or \"out\"".to_string()),
+        };
+        let payload = value["payload"].as_str().and_then(unhex).ok_or("`payload` must be a hex string")?;
+        Ok(Record {
+            time: UNIX_EPOCH + Duration::from_millis(time_ms),
+            connection,
+            direction,
+            payload,
+        })
+    }
+}
+
+// `payload`, an inbound frame in `encoding`, with the credentials of its AuthRequests replaced by
+// `logging::REDACTED`, so a capture never holds secrets. Frames that do not decode are kept as they are.
+pub fn redact(encoding: Encoding, payload: &[u8]) -> Cow<'_, [u8]> {
+    let Ok(request) = encoding.decode::<ClientMessage>(payload) else {
+        return Cow::Borrowed(payload);
+    };
+    match logging::redact_credentials(&request) {
+        Cow::Owned(redacted) => Cow::Owned(encoding.encode(&redacted)),
+        Cow::Borrowed(_) => Cow::Borrowed(payload),
+    }
+}
+
+// Appends one JSON line per frame of every connection to a file. Frames are written as they are read
+// or sent, so a capture of a server that crashed is complete up to the crash.
+#[derive(Debug)]
+pub struct Capture {
+    file: Mutex<File>,
+}
+
+impl Capture {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Capture { file: Mutex::new(file) })
+    }
+
+    pub fn record(&self, connection: ConnectionId, direction: Direction, payload: &[u8]) {
+        let record = Record {
+            time: SystemTime::now(),
+            connection,
+            direction,
+            payload: payload.to_vec(),
+        };
+        let line = record.to_json() + "\n";
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::error!("Failed to write captured frame: {}", e);
+        }
+    }
+}
+
+// Reads every record of a capture file. Blank lines are skipped.
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            records.push(Record::from_json(&line).map_err(|e| invalid(number + 1, e))?);
+        }
+    }
+    Ok(records)
+}
+
+// A response of the replay that differs from the captured one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub connection: ConnectionId, // Connection of the capture.
+    pub response: usize,          // Position of the response on that connection, from 0.
+    pub expected: ServerMessage,
+    pub actual: Option<ServerMessage>, // `None` if the server closed the connection or did not answer in time.
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "connection {}, response {}:", self.connection, self.response)?;
+        writeln!(f, "- {:?}", self.expected)?;
+        match self.actual {
+            Some(ref actual) => write!(f, "+ {:?}", actual),
+            None => write!(f, "+ (no response)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplayReport {
+    pub connections: usize,
+    pub requests: usize,
+    pub responses: usize, // Responses compared, matching or not.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+// Sends the captured client frames of every connection to the server at `addr` and compares each
+// response with the captured one. Connections are replayed one at a time, in the order they first
+// appear; each request is sent once the responses captured before it have arrived, or `timeout` passed.
+// Compression and checksums follow the Welcome the server answers with, and a connection that started
+// in JSON is replayed in JSON, so the server must accept it. Requests that depend on a
+// fresh nonce, like HMAC authentication, cannot be replayed meaningfully.
+pub fn replay(records: &[Record], addr: &str, timeout: Duration) -> io::Result<ReplayReport> {
+    let mut order = Vec::new();
+    let mut connections: HashMap<ConnectionId, Vec<&Record>> = HashMap::new();
+    for record in records {
+        let frames = connections.entry(record.connection).or_insert_with(|| {
+            order.push(record.connection);
+            Vec::new()
+        });
+        frames.push(record);
+    }
+
+    let mut report = ReplayReport::default();
+    for id in order {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(timeout))?;
+        let mut codec = frame::Codec::new(MAX_RESPONSE_LEN);
+        let (mut closed, mut responses, mut encoding) = (false, 0, None);
+        report.connections += 1;
+        for record in &connections[&id] {
+            match record.direction {
+                Direction::Inbound => {
+                    let encoding = *encoding.get_or_insert_with(|| Encoding::detect(&record.payload));
+                    if encoding.decode::<ClientMessage>(&record.payload).is_err() {
+                        tracing::warn!("Replaying a frame of connection {} that is not a ClientMessage", id);
+                    }
+                    if !closed && codec.write(&mut stream, &record.payload).is_err() {
+                        closed = true;  // The server hung up; what remains is reported as missing.
+                    }
+                    report.requests += 1;
+                }
+                Direction::Outbound => {
+                    let encoding = encoding.unwrap_or_default();
+                    let expected: ServerMessage = encoding.decode(&record.payload).map_err(|e| {
+                        io::Error::new(ErrorKind::InvalidData, format!("captured response of connection {}: {}", id, e))
+                    })?;
+                    let actual: Option<ServerMessage> = if closed {
+                        None
+                    } else {
+                        match codec.read(&mut stream) {
+                            Ok(Some(payload)) => encoding.decode(&payload).ok(),
+                            Ok(None) | Err(_) => None,
+                        }
+                    };
+                    closed |= actual.is_none();
+                    if let Some(server_message::Message::Welcome(ref welcome)) = actual.as_ref().and_then(|actual| actual.message.as_ref()) {
+                        let compression = CompressionAlgorithm::try_from(welcome.compression).unwrap_or(CompressionAlgorithm::None);
+                        let checksums = welcome.capabilities.contains(&(Capability::Checksums as i32));
+                        codec = codec.with_compression(compression, frame::DEFAULT_COMPRESSION_THRESHOLD).with_checksums(checksums);
+                    }
+                    if actual.as_ref() != Some(&expected) {
+                        report.mismatches.push(Mismatch {
+                            connection: id,
+                            response: responses,
+                            expected,
+                            actual,
+                        });
+                    }
+                    responses += 1;
+                    report.responses += 1;
+                }
+            }
+        }
+    }
+    Ok(report)
+}
@@ -1,18 +1,43 @@
-use embedded_recruitment_task::message::{client_message, ServerMessage};
+use embedded_recruitment_task::{
+    auth, compression, echo,
+    encoding::Encoding,
+    frame::{self, FrameError},
+    handshake,
+    message::{
+        admin_request, admin_response, auth_request, AdminRequest, AdminResponse, client_message, server_message, AuthRequest, AuthResponse, BatchRequest, BinaryEchoRequest,
+        Capability, ClientMessage, CompressionAlgorithm, DigestAlgorithm, Hello, Ping, ServerMessage, Welcome,
+    },
+    tls::Stream,
+};
 use prost::Message;
 use log::info;
 use log::error;
+use log::warn;
 use std::{
     io::{self, Read, Write},
     net::{SocketAddr, TcpStream, ToSocketAddrs},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+// Largest response frame the client accepts.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
 pub struct Client {
     ip: String,
     port: u32,
     timeout: Duration,
-    stream: Option<TcpStream>,
+    stream: Option<Stream>,
+    tls: Option<(Arc<rustls::ClientConfig>, String)>, // Client configuration and expected server name.
+    next_nonce: u64,
+    compression: CompressionAlgorithm, // Negotiated by `hello`; reset on every new connection.
+    checksums: bool,                   // Likewise.
+    corrupted_frames: u64,
+    encoding: Encoding,
 }
 
 impl Client {
@@ -22,9 +47,27 @@ impl Client {
             port,
             timeout: Duration::from_millis(timeout_ms),
             stream: None,
+            tls: None,
+            next_nonce: 0,
+            compression: CompressionAlgorithm::None,
+            checksums: false,
+            corrupted_frames: 0,
+            encoding: Encoding::Protobuf,
         }
     }
 
+    // Encodes messages this way from now on; the server must accept it.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    // Connects over TLS from now on, verifying the server certificate against `server_name`.
+    pub fn with_tls(mut self, config: Arc<rustls::ClientConfig>, server_name: &str) -> Self {
+        self.tls = Some((config, server_name.to_string()));
+        self
+    }
+
     pub fn connect(&mut self) -> io::Result<()> {
         let address = format!("{}:{}", self.ip, self.port);
         let socket_addrs: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
@@ -37,29 +80,49 @@ impl Client {
         }
 
         let stream = TcpStream::connect_timeout(&socket_addrs[0], self.timeout)?;
+        let stream = match self.tls {
+            Some((ref config, ref server_name)) => {
+                stream.set_read_timeout(Some(self.timeout))?;  // Bound the TLS handshake.
+                let stream = Stream::connect(stream, config.clone(), server_name)?;
+                stream.set_read_timeout(None)?;
+                stream
+            }
+            None => Stream::Plain(stream),
+        };
         self.stream = Some(stream);
+        self.compression = CompressionAlgorithm::None;
+        self.checksums = false;
         println!("Connected to the server!");
         Ok(())
     }
 
     pub fn disconnect(&mut self) -> io::Result<()> {
-        if let Some(stream) = self.stream.take() {
-            stream.shutdown(std::net::Shutdown::Both)?;
+        if let Some(mut stream) = self.stream.take() {
+            stream.shutdown()?;
         }
         println!("Disconnected from the server!");
         Ok(())
     }
 
     pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
+        self.send_with_request_id(message, 0)
+    }
+
+    // Sends a message tagged with `request_id`, which the server echoes once request IDs are negotiated.
+    pub fn send_with_request_id(&mut self, message: client_message::Message, request_id: u64) -> io::Result<()> {
+        let codec = self.codec();
         if let Some(ref mut stream) = self.stream {
-            let mut buffer = Vec::new();
-    
-            // Encode the message (returns `()`)
-            message.encode(&mut buffer);
+            let client_message = ClientMessage {
+                message: Some(message),
+                request_id,
+            };
+
+            // Encode the message
+            let buffer = self.encoding.encode(&client_message);
+            let message = client_message.message.unwrap();
     
-            // Write the encoded message to the stream
-            stream.write_all(&buffer)?;
-            stream.flush()?;
+            // Write the encoded message to the stream as one length-prefixed frame
+            codec.write(stream, &buffer)?;
 
             println!("Sent message: {:?}", message);
             Ok(())
@@ -73,21 +136,26 @@ impl Client {
     }
     
     pub fn receive(&mut self) -> io::Result<ServerMessage> {
+        let codec = self.codec();
         if let Some(ref mut stream) = self.stream {
             info!("Receiving message from the server");
-            let mut buffer = vec![0u8; 1024]; // Buffer for incoming data
-            let bytes_read = stream.read(&mut buffer)?; // Read data from the stream
-            if bytes_read == 0 {
+            // Read one frame from the stream
+            let frame = codec.read(stream).inspect_err(|e| {
+                if FrameError::from_io(e).is_some_and(FrameError::is_corruption) {
+                    self.corrupted_frames += 1;
+                }
+            })?;
+            let Some(buffer) = frame else {
                 // Error handling for server disconnection
                 return Err(io::Error::new(
                     io::ErrorKind::ConnectionAborted,
                     "Server disconnected",
                 ));
-            }
-            info!("Received {} bytes from the server", bytes_read);
+            };
+            info!("Received {} bytes from the server", buffer.len());
 
-             // Decode the message using prost
-            ServerMessage::decode(&buffer[..bytes_read]).map_err(|e| {
+             // Decode the message
+            self.encoding.decode::<ServerMessage>(&buffer).map_err(|e| {
                 io::Error::new(
                     io::ErrorKind::InvalidData,
                     format!("Failed to decode ServerMessage: {}", e),
@@ -101,5 +169,447 @@ impl Client {
             ))
         }
     }
+
+    fn codec(&self) -> frame::Codec {
+        frame::Codec::new(MAX_FRAME_SIZE)
+            .with_compression(self.compression, frame::DEFAULT_COMPRESSION_THRESHOLD)
+            .with_checksums(self.checksums)
+    }
+
+    // Number of received frames that failed their checksum.
+    pub fn corrupted_frames(&self) -> u64 {
+        self.corrupted_frames
+    }
+
+    // Compression negotiated for the current connection.
+    pub fn compression(&self) -> CompressionAlgorithm {
+        self.compression
+    }
+
+    // Performs the protocol handshake; must be the first message on the connection.
+    // Requesting `Capability::Compression` offers every algorithm this build supports.
+    pub fn hello(&mut self, client_name: &str, capabilities: &[Capability]) -> io::Result<Welcome> {
+        self.hello_with_version(handshake::PROTOCOL_VERSION, client_name, capabilities)
+    }
+
+    pub fn hello_with_version(
+        &mut self,
+        protocol_version: u32,
+        client_name: &str,
+        capabilities: &[Capability],
+    ) -> io::Result<Welcome> {
+        let hello = Hello {
+            protocol_version,
+            client_name: client_name.to_string(),
+            capabilities: capabilities.iter().map(|capability| *capability as i32).collect(),
+            compression: if capabilities.contains(&Capability::Compression) {
+                compression::SUPPORTED.iter().map(|algorithm| *algorithm as i32).collect()
+            } else {
+                Vec::new()
+            },
+        };
+        self.send(client_message::Message::Hello(hello))?;
+
+        match self.receive()?.message {
+            Some(server_message::Message::Welcome(welcome)) => {
+                self.compression = welcome.compression();  // Applies to every frame after the Welcome.
+                self.checksums = welcome.capabilities.contains(&(Capability::Checksums as i32));
+                Ok(welcome)
+            }
+            Some(server_message::Message::Error(e)) => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("Handshake rejected: {}", e.message),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected Welcome",
+            )),
+        }
+    }
+
+    // Authenticates the connection with a bearer token.
+    pub fn authenticate_token(&mut self, identity: &str, token: &str) -> io::Result<AuthResponse> {
+        self.send_auth(identity, auth_request::Credential::Token(token.to_string()))?;
+        self.receive_auth()
+    }
+
+    // Authenticates by signing a server challenge, so `secret` never crosses the wire.
+    pub fn authenticate_hmac(&mut self, identity: &str, secret: &str) -> io::Result<AuthResponse> {
+        self.send_auth(identity, auth_request::Credential::Challenge(true))?;
+        let nonce = match self.receive()?.message {
+            Some(server_message::Message::AuthChallenge(challenge)) => challenge.nonce,
+            Some(server_message::Message::Error(e)) => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, e.message))
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected AuthChallenge")),
+        };
+        self.send_auth(identity, auth_request::Credential::Signature(auth::sign(secret, &nonce)))?;
+        self.receive_auth()
+    }
+
+    fn send_auth(&mut self, identity: &str, credential: auth_request::Credential) -> io::Result<()> {
+        self.send(client_message::Message::AuthRequest(AuthRequest {
+            identity: identity.to_string(),
+            credential: Some(credential),
+        }))
+    }
+
+    fn receive_auth(&mut self) -> io::Result<AuthResponse> {
+        match self.receive()?.message {
+            Some(server_message::Message::AuthResponse(response)) => Ok(response),
+            Some(server_message::Message::Error(e)) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Authentication rejected ({:?}): {}", e.code(), e.message),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected AuthResponse",
+            )),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    // Sends a Ping and waits up to the client timeout for the matching Pong. Returns the round-trip time.
+    pub fn ping(&mut self) -> io::Result<Duration> {
+        self.next_nonce += 1;
+        let nonce = self.next_nonce;
+        let started = Instant::now();
+        self.send(client_message::Message::Ping(Ping { nonce }))?;
+
+        if let Some(ref stream) = self.stream {
+            stream.set_read_timeout(Some(self.timeout))?;
+        }
+        let response = self.receive();
+        if let Some(ref stream) = self.stream {
+            stream.set_read_timeout(None)?;
+        }
+
+        match response?.message {
+            Some(server_message::Message::Pong(pong)) if pong.nonce == nonce => Ok(started.elapsed()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected reply to Ping",
+            )),
+        }
+    }
+
+    // Sends `size` random bytes as a BinaryEchoRequest and checks that the echo and the server's
+    // digest match the original payload bit-for-bit.
+    pub fn verify_binary_echo(&mut self, size: usize, digest: DigestAlgorithm) -> io::Result<()> {
+        let payload = random_payload(size);
+        let request = BinaryEchoRequest {
+            payload: payload.clone(),
+            digest: digest as i32,
+        };
+        self.send(client_message::Message::BinaryEchoRequest(request))?;
+
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        match self.receive()?.message {
+            Some(server_message::Message::BinaryEchoResponse(response)) => {
+                if let Some(offset) = response.payload.iter().zip(&payload).position(|(a, b)| a != b) {
+                    return Err(invalid(format!("Payload differs at byte {}", offset)));
+                }
+                if response.payload.len() != payload.len() {
+                    return Err(invalid(format!(
+                        "Sent {} bytes but {} came back",
+                        payload.len(),
+                        response.payload.len()
+                    )));
+                }
+                if response.digest != echo::digest(digest, &payload) {
+                    return Err(invalid("Server digest does not match the payload".to_string()));
+                }
+                Ok(())
+            }
+            _ => Err(invalid("Expected BinaryEchoResponse".to_string())),
+        }
+    }
+
+    // Starts building a BatchRequest that is sent as a single frame.
+    pub fn batch(&mut self) -> BatchBuilder<'_> {
+        BatchBuilder {
+            client: self,
+            messages: Vec::new(),
+            parallel: false,
+        }
+    }
+}
+
+// What a heartbeat does once the server stops answering pings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatAction {
+    Reconnect, // Drop the dead connection and connect again.
+    Fail,      // Drop the dead connection, record the error and stop.
+}
+
+// Background thread that pings the server through a shared client every `interval`.
+pub struct Heartbeat {
+    stop: Arc<AtomicBool>,
+    reconnects: Arc<AtomicU32>,
+    last_error: Arc<Mutex<Option<io::Error>>>,
+    handle: Option<JoinHandle<()>>,
 }
 
+impl Heartbeat {
+    pub fn start(client: Arc<Mutex<Client>>, interval: Duration, action: HeartbeatAction) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let reconnects = Arc::new(AtomicU32::new(0));
+        let last_error = Arc::new(Mutex::new(None));
+
+        let handle = {
+            let (stop, reconnects, last_error) = (stop.clone(), reconnects.clone(), last_error.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    // Sleep in short steps so `stop` takes effect quickly.
+                    let deadline = Instant::now() + interval;
+                    while Instant::now() < deadline && !stop.load(Ordering::SeqCst) {
+                        thread::sleep(Duration::from_millis(10).min(interval));
+                    }
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let mut client = client.lock().unwrap();
+                    if !client.is_connected() && action == HeartbeatAction::Fail {
+                        continue;  // Nothing to watch until the owner connects.
+                    }
+                    let error = match client.ping() {
+                        Ok(_) => continue,
+                        Err(e) => e,
+                    };
+
+                    warn!("Heartbeat failed: {}", error);
+                    let _ = client.disconnect();
+                    if action == HeartbeatAction::Fail {
+                        *last_error.lock().unwrap() = Some(error);
+                        break;
+                    }
+                    match client.connect() {
+                        Ok(()) => {
+                            reconnects.fetch_add(1, Ordering::SeqCst);
+                        }
+                        Err(e) => *last_error.lock().unwrap() = Some(e),
+                    }
+                }
+            })
+        };
+
+        Heartbeat {
+            stop,
+            reconnects,
+            last_error,
+            handle: Some(handle),
+        }
+    }
+
+    // Number of successful reconnects after a failed heartbeat.
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects.load(Ordering::SeqCst)
+    }
+
+    // The error that made the heartbeat give up or the last failed reconnect, if any.
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.last_error.lock().unwrap().take()
+    }
+
+    // Stops the heartbeat thread and waits for it to exit.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Generates `size` pseudo-random bytes (xorshift64*, seeded from the clock).
+pub fn random_payload(size: usize) -> Vec<u8> {
+    let mut state = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0x9E37_79B9_7F4A_7C15, |d| d.as_nanos() as u64)
+        | 1;
+    let mut payload = Vec::with_capacity(size + 8);
+    while payload.len() < size {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        payload.extend_from_slice(&state.wrapping_mul(0x2545_F491_4F6C_DD1D).to_le_bytes());
+    }
+    payload.truncate(size);
+    payload
+}
+
+pub struct BatchBuilder<'a> {
+    client: &'a mut Client,
+    messages: Vec<ClientMessage>,
+    parallel: bool,
+}
+
+impl BatchBuilder<'_> {
+    pub fn push(mut self, message: client_message::Message) -> Self {
+        self.messages.push(ClientMessage {
+            message: Some(message),
+            ..Default::default()
+        });
+        self
+    }
+
+    // Lets the server process the entries concurrently; responses still come back in request order.
+    pub fn parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    // Sends the batch and returns one response per entry.
+    pub fn send(self) -> io::Result<Vec<ServerMessage>> {
+        let batch = BatchRequest {
+            messages: self.messages,
+            parallel: self.parallel,
+        };
+        self.client.send(client_message::Message::BatchRequest(batch))?;
+
+        match self.client.receive()?.message {
+            Some(server_message::Message::BatchResponse(response)) => Ok(response.responses),
+            Some(server_message::Message::Error(e)) => Err(io::Error::other(format!("Batch rejected: {}", e.message))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected BatchResponse",
+            )),
+        }
+    }
+}
+
+trait AdminStream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> AdminStream for S {}
+
+// Sends commands to a server's admin listener, with the same credentials on every request.
+pub struct AdminClient {
+    stream: Box<dyn AdminStream>,
+    identity: String,
+    token: String,
+}
+
+impl AdminClient {
+    pub fn connect(addr: &str, identity: &str, token: &str) -> io::Result<Self> {
+        Ok(Self::with_stream(Box::new(TcpStream::connect(addr)?), identity, token))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix(path: &std::path::Path, identity: &str, token: &str) -> io::Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        Ok(Self::with_stream(Box::new(stream), identity, token))
+    }
+
+    fn with_stream(stream: Box<dyn AdminStream>, identity: &str, token: &str) -> Self {
+        AdminClient {
+            stream,
+            identity: identity.to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    pub fn request(&mut self, command: admin_request::Command) -> io::Result<admin_response::Result> {
+        let request = AdminRequest {
+            identity: self.identity.clone(),
+            token: self.token.clone(),
+            command: Some(command),
+        };
+        let codec = frame::Codec::new(MAX_FRAME_SIZE);
+        codec.write(&mut self.stream, &request.encode_to_vec())?;
+        let Some(buffer) = codec.read(&mut self.stream)? else {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Admin listener disconnected"));
+        };
+        let response = AdminResponse::decode(&buffer[..]).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Failed to decode AdminResponse: {}", e))
+        })?;
+        response.result.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "AdminResponse contained no result"))
+    }
+}
+
+// Sends an HTTP/1.1 request and returns the status code and body of the response.
+pub fn http_send(addr: &str, method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, addr);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Malformed HTTP response: {:?}", response));
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok()).ok_or_else(invalid)?;
+    Ok((status, body.to_string()))
+}
+
+// Sends a bodyless request.
+pub fn http_request(addr: &str, method: &str, path: &str) -> io::Result<(u16, String)> {
+    http_send(addr, method, path, &[], "")
+}
+
+pub fn http_get(addr: &str, path: &str) -> io::Result<(u16, String)> {
+    http_request(addr, "GET", path)
+}
+
+// A browser-style client of a server's WebSocket listener: each request is one binary message.
+pub struct WebSocketClient {
+    pub socket: tungstenite::WebSocket<TcpStream>,
+}
+
+impl WebSocketClient {
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let (socket, _) = tungstenite::client(format!("ws://{}/", addr), stream).map_err(io::Error::other)?;
+        Ok(WebSocketClient { socket })
+    }
+
+    pub fn send(&mut self, message: client_message::Message, request_id: u64) -> io::Result<()> {
+        let message = ClientMessage { message: Some(message), request_id };
+        self.socket.send(tungstenite::Message::Binary(message.encode_to_vec())).map_err(io::Error::other)
+    }
+
+    // Waits for the next binary message, skipping pongs.
+    pub fn receive(&mut self) -> io::Result<ServerMessage> {
+        loop {
+            match self.socket.read().map_err(io::Error::other)? {
+                tungstenite::Message::Binary(payload) => {
+                    return ServerMessage::decode(&payload[..]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+                tungstenite::Message::Pong(_) => {}
+                other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected WebSocket message: {:?}", other))),
+            }
+        }
+    }
+
+    pub fn request(&mut self, message: client_message::Message, request_id: u64) -> io::Result<ServerMessage> {
+        self.send(message, request_id)?;
+        self.receive()
+    }
+
+    // Reads until the server closes and returns its close code.
+    pub fn close_code(&mut self) -> io::Result<u16> {
+        loop {
+            match self.socket.read().map_err(io::Error::other)? {
+                tungstenite::Message::Close(frame) => return Ok(frame.map_or(1005, |frame| frame.code.into())),
+                other => info!("Ignoring WebSocket message before the close: {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use crate::message::CompressionAlgorithm;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecompressError(pub String);
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to decompress frame: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecompressError {}
+
+// Algorithms this build can compress and decompress, in order of preference.
+pub const SUPPORTED: [CompressionAlgorithm; 2] = [CompressionAlgorithm::Lz4, CompressionAlgorithm::Deflate];
+
+pub fn compress(algorithm: CompressionAlgorithm, data: &[u8]) -> Vec<u8> {
+    match algorithm {
+        CompressionAlgorithm::None => data.to_vec(),
+        CompressionAlgorithm::Lz4 => lz4_flex::block::compress(data),
+        CompressionAlgorithm::Deflate => miniz_oxide::deflate::compress_to_vec(data, 6),
+    }
+}
+
+// Decompresses `data`, which must expand to exactly `expected_len` bytes. Neither algorithm is
+// allowed to produce more than that, so a forged length cannot be used to exhaust memory.
+pub fn decompress(algorithm: CompressionAlgorithm, data: &[u8], expected_len: usize) -> Result<Vec<u8>, DecompressError> {
+    let output = match algorithm {
+        CompressionAlgorithm::None => data.to_vec(),
+        CompressionAlgorithm::Lz4 => {
+            lz4_flex::block::decompress(data, expected_len).map_err(|e| DecompressError(e.to_string()))?
+        }
+        CompressionAlgorithm::Deflate => miniz_oxide::inflate::decompress_to_vec_with_limit(data, expected_len)
+            .map_err(|e| DecompressError(e.to_string()))?,
+    };
+
+    if output.len() != expected_len {
+        return Err(DecompressError(format!(
+            "expected {} bytes but got {}",
+            expected_len,
+            output.len()
+        )));
+    }
+    Ok(output)
+}
@@ -0,0 +1,104 @@
+use crate::admin::AdminConfig;
+use crate::audit::AuditSink;
+use crate::auth::CredentialStore;
+use crate::capture::Capture;
+use crate::deadline::MinTransferRate;
+use crate::eval::EvalLimits;
+use crate::compression;
+use crate::frame;
+use crate::logging::LogConfig;
+use crate::message::{Capability, CompressionAlgorithm};
+use crate::policy::Policy;
+use crate::ratelimit::RateLimits;
+use std::{sync::Arc, time::Duration};
+
+// Tunables shared by every connection of a `Server`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub max_frame_size: usize, // Largest accepted frame payload in bytes.
+    pub max_batch_size: usize, // Largest number of entries accepted in one BatchRequest.
+    pub max_echo_delay: Duration, // Longest delay an EchoRequest may ask for.
+    pub idle_timeout: Option<Duration>, // Connections silent for this long are closed; `None` never closes them.
+    pub frame_timeout: Option<Duration>, // A frame must be complete this long after its first byte.
+    pub min_transfer_rate: Option<MinTransferRate>, // Senders trickling a frame slower than this are closed.
+    pub eval_limits: EvalLimits,
+    pub server_name: String, // Reported to clients in Welcome.
+    pub capabilities: Vec<Capability>, // Capabilities the server is willing to grant.
+    pub require_handshake: bool, // Reject connections whose first message is not a Hello.
+    pub compression: Vec<CompressionAlgorithm>, // Compression algorithms the server accepts.
+    pub compression_threshold: usize, // Responses smaller than this are never compressed.
+    pub tls: Option<Arc<rustls::ServerConfig>>, // Serve TLS instead of plain TCP; see `tls::server_config`.
+    pub credentials: Option<Arc<dyn CredentialStore>>, // When set, connections must authenticate before other requests.
+    pub policy: Option<Arc<Policy>>, // Restricts the requests each identity may send; `None` allows everything.
+    pub audit: Option<Arc<dyn AuditSink>>, // Receives every request denied by `policy`.
+    pub rate_limits: RateLimits, // Unlimited by default. Fixed at startup; a reload that changes it is refused.
+    pub max_connections: Option<usize>, // Connections open at once; `None` is unlimited. Fixed at startup, likewise.
+    pub max_connections_per_ip: Option<usize>, // Connections open at once from one source address. Likewise.
+    pub admin: Option<AdminConfig>, // Separate listener for operators; ignored when the configuration is reloaded.
+    pub logging: LogConfig, // Payload logging, redaction and sampling; `max_request_logs_per_sec` is fixed at startup.
+    pub http_addr: Option<String>, // Serves `/metrics`, `/healthz` and `/readyz` over plain HTTP; likewise bound once at startup.
+    pub capture: Option<Arc<Capture>>, // Records every frame of every connection, e.g. for `capture::replay`.
+    pub accept_json: bool, // Connections whose first frame is JSON are answered in JSON; see `encoding::Encoding`.
+    pub json_addr: Option<String>, // Second listener whose connections always speak JSON; bound once at startup.
+    pub gateway_addr: Option<String>, // Serves requests as `POST /<kind>` with JSON bodies; see `gateway.rs`. Likewise.
+    pub websocket_addr: Option<String>, // Listener for WebSocket clients, one protobuf message per binary message. Likewise.
+}
+
+impl ServerConfig {
+    // Settings the server applies once when it is created and that differ in `reloaded`.
+    pub fn fixed_changes(&self, reloaded: &ServerConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.rate_limits != reloaded.rate_limits {
+            changed.push("rate_limits");
+        }
+        if self.max_connections != reloaded.max_connections {
+            changed.push("max_connections");
+        }
+        if self.max_connections_per_ip != reloaded.max_connections_per_ip {
+            changed.push("max_connections_per_ip");
+        }
+        if self.logging.max_request_logs_per_sec != reloaded.logging.max_request_logs_per_sec {
+            changed.push("logging.max_request_logs_per_sec");
+        }
+        changed
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_frame_size: 1024 * 1024,
+            max_batch_size: 256,
+            max_echo_delay: Duration::from_secs(10),
+            idle_timeout: Some(Duration::from_secs(300)),
+            frame_timeout: Some(Duration::from_secs(30)),
+            min_transfer_rate: None,
+            eval_limits: EvalLimits::default(),
+            server_name: concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_string(),
+            capabilities: vec![
+                Capability::RequestIds,
+                Capability::Batching,
+                Capability::Compression,
+                Capability::Checksums,
+            ],
+            require_handshake: false,
+            compression: compression::SUPPORTED.to_vec(),
+            compression_threshold: frame::DEFAULT_COMPRESSION_THRESHOLD,
+            tls: None,
+            credentials: None,
+            policy: None,
+            audit: None,
+            rate_limits: RateLimits::default(),
+            max_connections: None,
+            max_connections_per_ip: None,
+            admin: None,
+            logging: LogConfig::default(),
+            http_addr: None,
+            capture: None,
+            accept_json: false,
+            json_addr: None,
+            gateway_addr: None,
+            websocket_addr: None,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// Counts open connections and refuses new ones over the global or per-address limits.
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    max_connections: Option<usize>,
+    max_per_ip: Option<usize>,
+    open: Mutex<HashMap<IpAddr, usize>>,
+    rejected: AtomicU64,
+}
+
+// Holds one connection slot until dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: Option<usize>, max_per_ip: Option<usize>) -> Self {
+        ConnectionLimiter {
+            max_connections,
+            max_per_ip,
+            ..Default::default()
+        }
+    }
+
+    // Takes a slot for a connection from `ip`, or explains why there is none.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit, String> {
+        let mut open = self.open.lock().unwrap();
+        let total: usize = open.values().sum();
+        let from_ip = open.get(&ip).copied().unwrap_or(0);
+
+        let refusal = match (self.max_connections, self.max_per_ip) {
+            (Some(max), _) if total >= max => Some(format!("server is at its limit of {} connections", max)),
+            (_, Some(max)) if from_ip >= max => Some(format!("{} already has {} connections", ip, max)),
+            _ => None,
+        };
+        if let Some(refusal) = refusal {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+            return Err(refusal);
+        }
+
+        *open.entry(ip).or_insert(0) += 1;
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.open.lock().unwrap().values().sum()
+    }
+
+    pub fn open_connections_from(&self, ip: IpAddr) -> usize {
+        self.open.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+
+    // Connections refused since the limiter was created.
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,144 @@
+use crate::tls::Stream;
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read},
+    time::{Duration, Instant},
+};
+
+// How often a stalled frame is re-checked while no bytes arrive.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Slowest accepted sender: after `grace`, a frame must have arrived at `bytes_per_sec` on average.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MinTransferRate {
+    pub bytes_per_sec: f64,
+    pub grace: Duration,
+}
+
+// Why a sender was cut off. Carried inside the `io::Error` returned by `DeadlineReader`.
+#[derive(Debug)]
+pub enum Stalled {
+    FrameTimeout { limit: Duration },
+    TooSlow { received: u64, elapsed: Duration, min: f64 },
+}
+
+impl Stalled {
+    // Returns the stall wrapped in `error`, if any.
+    pub fn from_io(error: &io::Error) -> Option<&Stalled> {
+        error.get_ref().and_then(|e| e.downcast_ref::<Stalled>())
+    }
+}
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stalled::FrameTimeout { limit } => write!(f, "frame not completed within {:?}", limit),
+            Stalled::TooSlow { received, elapsed, min } => write!(
+                f,
+                "{} bytes in {:?} is below the minimum rate of {} bytes/s",
+                received, elapsed, min
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+impl From<Stalled> for io::Error {
+    fn from(stalled: Stalled) -> Self {
+        io::Error::new(ErrorKind::TimedOut, stalled)
+    }
+}
+
+// Reads one frame from a stream. Waiting for the frame to start is bounded by the idle timeout;
+// once its first byte arrives the rest must follow within `frame_timeout` and above `min_rate`.
+// On a WebSocket, the frame starts with the first byte of the message on the wire.
+pub struct DeadlineReader<'a> {
+    stream: &'a mut Stream,
+    idle_timeout: Option<Duration>,
+    frame_timeout: Option<Duration>,
+    min_rate: Option<MinTransferRate>,
+    started: Option<Instant>,
+    received: u64,
+    read_timeout: Option<Option<Duration>>, // Last timeout set on the socket, to skip redundant updates.
+}
+
+impl<'a> DeadlineReader<'a> {
+    pub fn new(
+        stream: &'a mut Stream,
+        idle_timeout: Option<Duration>,
+        frame_timeout: Option<Duration>,
+        min_rate: Option<MinTransferRate>,
+    ) -> Self {
+        DeadlineReader {
+            stream,
+            idle_timeout,
+            frame_timeout,
+            min_rate,
+            started: None,
+            received: 0,
+            read_timeout: None,
+        }
+    }
+
+    // Fails if the frame in progress is overdue, otherwise returns how long the next read may block.
+    fn check(&self, (started, received): (Instant, u64)) -> Result<Duration, Stalled> {
+        let elapsed = started.elapsed();
+        let mut wait = CHECK_INTERVAL;
+        if let Some(limit) = self.frame_timeout {
+            if elapsed >= limit {
+                return Err(Stalled::FrameTimeout { limit });
+            }
+            wait = wait.min(limit - elapsed);
+        }
+        if let Some(min) = self.min_rate {
+            if elapsed > min.grace && (received as f64) < min.bytes_per_sec * elapsed.as_secs_f64() {
+                return Err(Stalled::TooSlow {
+                    received,
+                    elapsed,
+                    min: min.bytes_per_sec,
+                });
+            }
+        }
+        Ok(wait)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        if self.read_timeout != Some(timeout) {
+            self.stream.set_read_timeout(timeout)?;
+            self.read_timeout = Some(timeout);
+        }
+        Ok(())
+    }
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let progress = match self.started {
+                Some(started) => Some((started, self.received)),
+                None => self.stream.partial_frame(),
+            };
+            let timeout = match progress {
+                None => self.idle_timeout,
+                Some(progress) => Some(self.check(progress)?),
+            };
+            self.set_read_timeout(timeout)?;
+
+            match self.stream.read(buf) {
+                Ok(n) => {
+                    if n > 0 {
+                        self.started.get_or_insert_with(|| progress.map_or_else(Instant::now, |(started, _)| started));
+                        self.received += n as u64;
+                    }
+                    return Ok(n);
+                }
+                // Mid-frame timeouts only mean it is time to check the deadline again.
+                Err(ref e)
+                    if (self.started.is_some() || self.stream.partial_frame().is_some())
+                        && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::config::ServerConfig;
+use crate::message::{
+    BinaryEchoRequest, BinaryEchoResponse, DigestAlgorithm, EchoRequest, EchoResponse, ErrorCode, ErrorResponse,
+};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use sha2::{Digest, Sha256};
+use std::{
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+fn now_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64)
+}
+
+fn limit_exceeded(message: String) -> ErrorResponse {
+    ErrorResponse {
+        code: ErrorCode::LimitExceeded as i32,
+        message,
+    }
+}
+
+// Size in bytes of the content echoed for `request`, after uppercasing (which can triple the
+// length of a character) and padding with a possibly multi-byte character.
+pub fn response_size(request: &EchoRequest) -> usize {
+    let repeat = request.repeat.max(1) as usize;
+    let len = match request.uppercase {
+        true => request.content.chars().flat_map(char::to_uppercase).map(char::len_utf8).sum(),
+        false => request.content.len(),
+    };
+    let size = len.saturating_mul(repeat);
+    let pad_to = request.pad_to as usize;
+    if size >= pad_to {
+        return size;
+    }
+    let width = request.pad_char.chars().next().map_or(1, char::len_utf8);
+    size + (pad_to - size).div_ceil(width) * width
+}
+
+pub fn echo(request: &EchoRequest, config: &ServerConfig) -> Result<EchoResponse, ErrorResponse> {
+    let received_at_us = now_us();
+
+    // Check the size up front so a large `repeat` or `pad_to` cannot allocate past the frame limit.
+    let repeat = request.repeat.max(1) as usize;
+    let size = response_size(request);
+    if size > config.max_frame_size {
+        return Err(limit_exceeded(format!(
+            "echo of {} bytes exceeds the frame limit of {} bytes",
+            size, config.max_frame_size
+        )));
+    }
+    let delay = Duration::from_millis(request.delay_ms.into());
+    if delay > config.max_echo_delay {
+        return Err(limit_exceeded(format!(
+            "echo delay of {:?} exceeds the limit of {:?}",
+            delay, config.max_echo_delay
+        )));
+    }
+
+    let mut content = request.content.repeat(repeat);
+    if request.reverse {
+        content = content.chars().rev().collect();
+    }
+    if request.uppercase {
+        content = content.to_uppercase();
+    }
+    let pad_char = request.pad_char.chars().next().unwrap_or(' ');
+    while content.len() < request.pad_to as usize {
+        content.push(pad_char);
+    }
+
+    if !delay.is_zero() {
+        thread::sleep(delay);
+    }
+
+    let (received_at_us, sent_at_us) = if request.timestamps {
+        (received_at_us, now_us())
+    } else {
+        (0, 0)
+    };
+    Ok(EchoResponse {
+        content,
+        received_at_us,
+        sent_at_us,
+    })
+}
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+// Digest of `data` as reported in a BinaryEchoResponse; empty for `DigestAlgorithm::None`.
+pub fn digest(algorithm: DigestAlgorithm, data: &[u8]) -> Vec<u8> {
+    match algorithm {
+        DigestAlgorithm::None => Vec::new(),
+        DigestAlgorithm::Crc32 => CRC32.checksum(data).to_be_bytes().to_vec(),
+        DigestAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+    }
+}
+
+pub fn binary_echo(request: BinaryEchoRequest) -> Result<BinaryEchoResponse, ErrorResponse> {
+    let algorithm = DigestAlgorithm::try_from(request.digest).map_err(|_| ErrorResponse {
+        code: ErrorCode::InvalidArgument as i32,
+        message: format!("unknown digest algorithm {}", request.digest),
+    })?;
+
+    Ok(BinaryEchoResponse {
+        digest: digest(algorithm, &request.payload),
+        payload: request.payload,
+    })
+}
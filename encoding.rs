@@ -0,0 +1,112 @@
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
+
+// How messages are encoded inside frames. The frames themselves, with their length header,
+// compression and checksums, are the same for both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Protobuf,
+    // The serde mapping of the generated types, e.g. `{"request_id":1,"message":{"echo_message":{"content":"hi"}}}`.
+    // JSON has no NaN or infinities, so non-finite floats are written as the strings `"NaN"`,
+    // `"Infinity"` and `"-Infinity"`; see `float`.
+    Json,
+}
+
+impl Encoding {
+    // Tells the encoding of a connection from its first payload. JSON messages start with `{`, which as
+    // a protobuf tag would be field 15 with the obsolete group wire type, so never starts a ClientMessage.
+    // Whitespace may come first, but `\n{` also starts a 123-byte EchoMessage, so then the whole
+    // payload must parse as JSON.
+    pub fn detect(payload: &[u8]) -> Self {
+        let start = payload.iter().position(|byte| !byte.is_ascii_whitespace()).unwrap_or(payload.len());
+        match payload.get(start) {
+            Some(b'{') if start == 0 || serde_json::from_slice::<IgnoredAny>(payload).is_ok() => Encoding::Json,
+            _ => Encoding::Protobuf,
+        }
+    }
+
+    pub fn encode<M: prost::Message + Serialize>(self, message: &M) -> Vec<u8> {
+        match self {
+            Encoding::Protobuf => message.encode_to_vec(),
+            Encoding::Json => serde_json::to_vec(message).expect("Generated messages always serialize to JSON"),
+        }
+    }
+
+    pub fn decode<M: prost::Message + Default + DeserializeOwned>(self, payload: &[u8]) -> Result<M, String> {
+        match self {
+            Encoding::Protobuf => M::decode(payload).map_err(|e| e.to_string()),
+            Encoding::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// Bytes fields in the JSON encoding: standard base64 with padding.
+pub mod base64 {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        STANDARD.decode(text).map_err(D::Error::custom)
+    }
+}
+
+// Double fields in the JSON encoding: numbers, or `"NaN"`, `"Infinity"` and `"-Infinity"`.
+pub mod float {
+    use serde::{
+        de::{Error, Visitor},
+        Deserializer, Serializer,
+    };
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        match *value {
+            value if value.is_nan() => serializer.serialize_str("NaN"),
+            f64::INFINITY => serializer.serialize_str("Infinity"),
+            f64::NEG_INFINITY => serializer.serialize_str("-Infinity"),
+            value => serializer.serialize_f64(value),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        deserializer.deserialize_any(FloatVisitor)
+    }
+
+    struct FloatVisitor;
+
+    impl Visitor<'_> for FloatVisitor {
+        type Value = f64;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a number, \"NaN\", \"Infinity\" or \"-Infinity\"")
+        }
+
+        fn visit_f64<E: Error>(self, value: f64) -> Result<f64, E> {
+            Ok(value)
+        }
+
+        fn visit_i64<E: Error>(self, value: i64) -> Result<f64, E> {
+            Ok(value as f64)
+        }
+
+        fn visit_u64<E: Error>(self, value: u64) -> Result<f64, E> {
+            Ok(value as f64)
+        }
+
+        fn visit_str<E: Error>(self, value: &str) -> Result<f64, E> {
+            match value {
+                "NaN" => Ok(f64::NAN),
+                "Infinity" => Ok(f64::INFINITY),
+                "-Infinity" => Ok(f64::NEG_INFINITY),
+                _ => Err(E::invalid_value(serde::de::Unexpected::Str(value), &self)),
+            }
+        }
+    }
+}
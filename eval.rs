@@ -0,0 +1,594 @@
+use crate::message::{self, eval_response, number, EvalRequest, EvalResponse};
+use std::{collections::HashMap, fmt};
+
+// Limits applied to every evaluation so a hostile expression cannot exhaust the worker thread.
+#[derive(Debug, Clone, Copy)]
+pub struct EvalLimits {
+    pub max_length: usize, // Maximum expression length in characters.
+    pub max_depth: usize,  // Maximum nesting depth of the parsed expression.
+    pub max_steps: usize,  // Maximum number of nodes visited while evaluating.
+}
+
+impl Default for EvalLimits {
+    fn default() -> Self {
+        EvalLimits {
+            max_length: 4096,
+            max_depth: 64,
+            max_steps: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+}
+
+impl Value {
+    fn as_f64(self) -> f64 {
+        match self {
+            Value::Int(i) => i as f64,
+            Value::Float(f) => f,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalErrorKind {
+    Syntax,
+    UnknownVariable,
+    UnknownFunction,
+    Arity,
+    DivisionByZero,
+    Overflow,
+    DepthLimit,
+    StepLimit,
+    LengthLimit,
+}
+
+// An evaluation failure. `column` is the 1-based character position the error refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalError {
+    pub kind: EvalErrorKind,
+    pub message: String,
+    pub column: usize,
+}
+
+impl EvalError {
+    fn new(kind: EvalErrorKind, column: usize, message: impl Into<String>) -> Self {
+        EvalError {
+            kind,
+            message: message.into(),
+            column,
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Int(i64),
+    Float(f64),
+    Ident(String),
+    Op(char),
+    LParen,
+    RParen,
+    Comma,
+    End,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, EvalError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c.is_ascii_digit() || (c == '.' && chars.get(i + 1).is_some_and(|n| n.is_ascii_digit())) {
+            let start = i;
+            let mut is_float = false;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            if i < chars.len() && chars[i] == '.' {
+                is_float = true;
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    is_float = true;
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+
+            let text: String = chars[start..i].iter().collect();
+            let kind = if is_float {
+                text.parse::<f64>()
+                    .map(TokenKind::Float)
+                    .map_err(|_| EvalError::new(EvalErrorKind::Syntax, column, format!("invalid number '{}'", text)))?
+            } else {
+                text.parse::<i64>().map(TokenKind::Int).map_err(|_| {
+                    EvalError::new(EvalErrorKind::Overflow, column, format!("integer literal '{}' is out of range", text))
+                })?
+            };
+            tokens.push(Token { kind, column });
+            continue;
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token {
+                kind: TokenKind::Ident(chars[start..i].iter().collect()),
+                column,
+            });
+            continue;
+        }
+
+        let kind = match c {
+            '+' | '-' | '*' | '/' | '%' | '^' => TokenKind::Op(c),
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            ',' => TokenKind::Comma,
+            _ => {
+                return Err(EvalError::new(
+                    EvalErrorKind::Syntax,
+                    column,
+                    format!("unexpected character '{}'", c),
+                ))
+            }
+        };
+        tokens.push(Token { kind, column });
+        i += 1;
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::End,
+        column: chars.len() + 1,
+    });
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(Value),
+    Variable(String),
+    Neg(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Call(String, Vec<Node>),
+}
+
+#[derive(Debug)]
+struct Node {
+    expr: Expr,
+    column: usize,
+}
+
+// Recursive-descent parser. Precedence from lowest to highest: `+ -`, `* / %`, unary minus, `^`.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn enter(&mut self, column: usize) -> Result<(), EvalError> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(EvalError::new(
+                EvalErrorKind::DepthLimit,
+                column,
+                format!("expression nested deeper than {} levels", self.max_depth),
+            ));
+        }
+        Ok(())
+    }
+
+    fn parse(mut self) -> Result<Node, EvalError> {
+        let node = self.expression()?;
+        let token = self.peek();
+        if token.kind != TokenKind::End {
+            return Err(EvalError::new(EvalErrorKind::Syntax, token.column, "unexpected token after expression"));
+        }
+        Ok(node)
+    }
+
+    // Each operator of a chain like `1 + 2 + 3` nests the tree one level deeper, so it counts toward the depth.
+    fn expression(&mut self) -> Result<Node, EvalError> {
+        let (mut left, mut levels) = (self.term()?, 0);
+        while let TokenKind::Op(op @ ('+' | '-')) = self.peek().kind {
+            let column = self.next().column;
+            self.enter(column)?;
+            levels += 1;
+            let right = self.term()?;
+            left = Node {
+                expr: Expr::Binary(op, Box::new(left), Box::new(right)),
+                column,
+            };
+        }
+        self.depth -= levels;
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Node, EvalError> {
+        let (mut left, mut levels) = (self.unary()?, 0);
+        while let TokenKind::Op(op @ ('*' | '/' | '%')) = self.peek().kind {
+            let column = self.next().column;
+            self.enter(column)?;
+            levels += 1;
+            let right = self.unary()?;
+            left = Node {
+                expr: Expr::Binary(op, Box::new(left), Box::new(right)),
+                column,
+            };
+        }
+        self.depth -= levels;
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, EvalError> {
+        match self.peek().kind {
+            TokenKind::Op('-') => {
+                let column = self.next().column;
+                self.enter(column)?;
+                let operand = self.unary()?;
+                self.depth -= 1;
+                Ok(Node {
+                    expr: Expr::Neg(Box::new(operand)),
+                    column,
+                })
+            }
+            TokenKind::Op('+') => {
+                let column = self.next().column;
+                self.enter(column)?;
+                let operand = self.unary();
+                self.depth -= 1;
+                operand
+            }
+            _ => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Result<Node, EvalError> {
+        let base = self.primary()?;
+        if let TokenKind::Op('^') = self.peek().kind {
+            let column = self.next().column;
+            self.enter(column)?;
+            let exponent = self.unary()?; // Right-associative: 2^3^2 == 2^(3^2).
+            self.depth -= 1;
+            return Ok(Node {
+                expr: Expr::Binary('^', Box::new(base), Box::new(exponent)),
+                column,
+            });
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Node, EvalError> {
+        let token = self.next();
+        let column = token.column;
+        match token.kind {
+            TokenKind::Int(i) => Ok(Node {
+                expr: Expr::Number(Value::Int(i)),
+                column,
+            }),
+            TokenKind::Float(f) => Ok(Node {
+                expr: Expr::Number(Value::Float(f)),
+                column,
+            }),
+            TokenKind::Ident(name) => {
+                if self.peek().kind != TokenKind::LParen {
+                    return Ok(Node {
+                        expr: Expr::Variable(name),
+                        column,
+                    });
+                }
+                self.next();
+                self.enter(column)?;
+                let mut args = Vec::new();
+                if self.peek().kind != TokenKind::RParen {
+                    loop {
+                        args.push(self.expression()?);
+                        if self.peek().kind == TokenKind::Comma {
+                            self.next();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                self.expect_rparen()?;
+                self.depth -= 1;
+                Ok(Node {
+                    expr: Expr::Call(name, args),
+                    column,
+                })
+            }
+            TokenKind::LParen => {
+                self.enter(column)?;
+                let inner = self.expression()?;
+                self.expect_rparen()?;
+                self.depth -= 1;
+                Ok(inner)
+            }
+            TokenKind::End => Err(EvalError::new(EvalErrorKind::Syntax, column, "unexpected end of expression")),
+            _ => Err(EvalError::new(EvalErrorKind::Syntax, column, "expected a number, variable or '('")),
+        }
+    }
+
+    fn expect_rparen(&mut self) -> Result<(), EvalError> {
+        let token = self.next();
+        if token.kind != TokenKind::RParen {
+            return Err(EvalError::new(EvalErrorKind::Syntax, token.column, "expected ')'"));
+        }
+        Ok(())
+    }
+}
+
+struct Evaluator<'a> {
+    variables: &'a HashMap<String, Value>,
+    steps: usize,
+    max_steps: usize,
+}
+
+impl Evaluator<'_> {
+    fn eval(&mut self, node: &Node) -> Result<Value, EvalError> {
+        self.steps += 1;
+        if self.steps > self.max_steps {
+            return Err(EvalError::new(
+                EvalErrorKind::StepLimit,
+                node.column,
+                format!("evaluation exceeded {} steps", self.max_steps),
+            ));
+        }
+
+        match &node.expr {
+            Expr::Number(value) => Ok(*value),
+            Expr::Variable(name) => self.variables.get(name).copied().ok_or_else(|| {
+                EvalError::new(EvalErrorKind::UnknownVariable, node.column, format!("unknown variable '{}'", name))
+            }),
+            Expr::Neg(operand) => match self.eval(operand)? {
+                Value::Int(i) => i.checked_neg().map(Value::Int).ok_or_else(|| overflow(node.column)),
+                Value::Float(f) => Ok(Value::Float(-f)),
+            },
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                binary(*op, left, right, node.column)
+            }
+            Expr::Call(name, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(self.eval(arg)?);
+                }
+                call(name, &values, node.column)
+            }
+        }
+    }
+}
+
+fn overflow(column: usize) -> EvalError {
+    EvalError::new(EvalErrorKind::Overflow, column, "integer overflow")
+}
+
+fn binary(op: char, left: Value, right: Value, column: usize) -> Result<Value, EvalError> {
+    if let (Value::Int(a), Value::Int(b)) = (left, right) {
+        return match op {
+            '+' => a.checked_add(b).map(Value::Int).ok_or_else(|| overflow(column)),
+            '-' => a.checked_sub(b).map(Value::Int).ok_or_else(|| overflow(column)),
+            '*' => a.checked_mul(b).map(Value::Int).ok_or_else(|| overflow(column)),
+            '/' | '%' if b == 0 => Err(EvalError::new(EvalErrorKind::DivisionByZero, column, "division by zero")),
+            // Integer division stays integral only when it is exact, otherwise it falls through to floats.
+            '/' => match a.checked_rem(b) {
+                Some(0) => a.checked_div(b).map(Value::Int).ok_or_else(|| overflow(column)),
+                Some(_) => Ok(Value::Float(a as f64 / b as f64)),
+                None => Err(overflow(column)),  // i64::MIN / -1
+            },
+            '%' => a.checked_rem(b).map(Value::Int).ok_or_else(|| overflow(column)),
+            '^' if b >= 0 => u32::try_from(b)
+                .ok()
+                .and_then(|b| a.checked_pow(b))
+                .map(Value::Int)
+                .ok_or_else(|| overflow(column)),
+            '^' => Ok(Value::Float((a as f64).powf(b as f64))),
+            _ => unreachable!("parser only produces known operators"),
+        };
+    }
+
+    let (a, b) = (left.as_f64(), right.as_f64());
+    match op {
+        '+' => Ok(Value::Float(a + b)),
+        '-' => Ok(Value::Float(a - b)),
+        '*' => Ok(Value::Float(a * b)),
+        '/' | '%' if b == 0.0 => Err(EvalError::new(EvalErrorKind::DivisionByZero, column, "division by zero")),
+        '/' => Ok(Value::Float(a / b)),
+        '%' => Ok(Value::Float(a % b)),
+        '^' => Ok(Value::Float(a.powf(b))),
+        _ => unreachable!("parser only produces known operators"),
+    }
+}
+
+fn call(name: &str, args: &[Value], column: usize) -> Result<Value, EvalError> {
+    let arity = |expected: usize| -> Result<(), EvalError> {
+        if args.len() != expected {
+            return Err(EvalError::new(
+                EvalErrorKind::Arity,
+                column,
+                format!("{}() takes {} argument(s), got {}", name, expected, args.len()),
+            ));
+        }
+        Ok(())
+    };
+
+    match name {
+        "abs" => {
+            arity(1)?;
+            match args[0] {
+                Value::Int(i) => i.checked_abs().map(Value::Int).ok_or_else(|| overflow(column)),
+                Value::Float(f) => Ok(Value::Float(f.abs())),
+            }
+        }
+        "min" | "max" => {
+            if args.is_empty() {
+                return Err(EvalError::new(EvalErrorKind::Arity, column, format!("{}() needs at least one argument", name)));
+            }
+            let pick_first = |a: Value, b: Value| if name == "min" { a.as_f64() <= b.as_f64() } else { a.as_f64() >= b.as_f64() };
+            Ok(args[1..].iter().fold(args[0], |best, &v| if pick_first(best, v) { best } else { v }))
+        }
+        "pow" => {
+            arity(2)?;
+            binary('^', args[0], args[1], column)
+        }
+        "sqrt" | "floor" | "ceil" | "round" | "exp" | "ln" | "sin" | "cos" | "tan" => {
+            arity(1)?;
+            let x = args[0].as_f64();
+            let result = match name {
+                "sqrt" => x.sqrt(),
+                "floor" => x.floor(),
+                "ceil" => x.ceil(),
+                "round" => x.round(),
+                "exp" => x.exp(),
+                "ln" => x.ln(),
+                "sin" => x.sin(),
+                "cos" => x.cos(),
+                _ => x.tan(),
+            };
+            Ok(Value::Float(result))
+        }
+        _ => Err(EvalError::new(EvalErrorKind::UnknownFunction, column, format!("unknown function '{}'", name))),
+    }
+}
+
+// Parses and evaluates `expression`, resolving identifiers against `variables`.
+pub fn evaluate(expression: &str, variables: &HashMap<String, Value>, limits: &EvalLimits) -> Result<Value, EvalError> {
+    let length = expression.chars().count();
+    if length > limits.max_length {
+        return Err(EvalError::new(
+            EvalErrorKind::LengthLimit,
+            limits.max_length + 1,
+            format!("expression longer than {} characters", limits.max_length),
+        ));
+    }
+
+    let tokens = tokenize(expression)?;
+    let ast = Parser {
+        tokens: &tokens,
+        pos: 0,
+        depth: 0,
+        max_depth: limits.max_depth,
+    }
+    .parse()?;
+
+    Evaluator {
+        variables,
+        steps: 0,
+        max_steps: limits.max_steps,
+    }
+    .eval(&ast)
+}
+
+impl From<Value> for message::Number {
+    fn from(value: Value) -> Self {
+        let value = match value {
+            Value::Int(i) => number::Value::Integer(i),
+            Value::Float(f) => number::Value::Float(f),
+        };
+        message::Number { value: Some(value) }
+    }
+}
+
+impl From<EvalErrorKind> for message::EvalErrorKind {
+    fn from(kind: EvalErrorKind) -> Self {
+        match kind {
+            EvalErrorKind::Syntax => message::EvalErrorKind::Syntax,
+            EvalErrorKind::UnknownVariable => message::EvalErrorKind::UnknownVariable,
+            EvalErrorKind::UnknownFunction => message::EvalErrorKind::UnknownFunction,
+            EvalErrorKind::Arity => message::EvalErrorKind::Arity,
+            EvalErrorKind::DivisionByZero => message::EvalErrorKind::DivisionByZero,
+            EvalErrorKind::Overflow => message::EvalErrorKind::Overflow,
+            EvalErrorKind::DepthLimit => message::EvalErrorKind::DepthLimit,
+            EvalErrorKind::StepLimit => message::EvalErrorKind::StepLimit,
+            EvalErrorKind::LengthLimit => message::EvalErrorKind::LengthLimit,
+        }
+    }
+}
+
+// Evaluates a decoded `EvalRequest` and packs the outcome into an `EvalResponse`.
+pub fn handle_request(request: &EvalRequest, limits: &EvalLimits) -> EvalResponse {
+    // Variables sent without a value are treated as undefined.
+    let variables: HashMap<String, Value> = request
+        .variables
+        .iter()
+        .filter_map(|(name, number)| {
+            let value = match number.value? {
+                number::Value::Integer(i) => Value::Int(i),
+                number::Value::Float(f) => Value::Float(f),
+            };
+            Some((name.clone(), value))
+        })
+        .collect();
+
+    let result = match evaluate(&request.expression, &variables, limits) {
+        Ok(value) => eval_response::Result::Value(value.into()),
+        Err(e) => eval_response::Result::Error(message::EvalError {
+            kind: message::EvalErrorKind::from(e.kind) as i32,
+            message: e.message,
+            column: e.column as u32,
+        }),
+    };
+    EvalResponse { result: Some(result) }
+}
@@ -0,0 +1,217 @@
+use crate::compression::{self, DecompressError};
+use crate::message::CompressionAlgorithm;
+use crc::{Crc, CRC_32_ISCSI};
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+};
+
+// Every message on the wire is preceded by a 4-byte big-endian header holding the payload length.
+// The top bit of the header marks a compressed payload, which starts with its uncompressed length
+// as another 4-byte big-endian integer. The next bit marks a 4-byte big-endian CRC32C trailer
+// computed over the header and payload.
+pub const HEADER_LEN: usize = 4;
+pub const TRAILER_LEN: usize = 4;
+pub const FLAG_COMPRESSED: u32 = 1 << 31;
+pub const FLAG_CHECKSUM: u32 = 1 << 30;
+pub const LENGTH_MASK: u32 = FLAG_CHECKSUM - 1;
+
+const CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+fn checksum(header: &[u8], body: &[u8]) -> u32 {
+    let mut digest = CRC32C.digest();
+    digest.update(header);
+    digest.update(body);
+    digest.finalize()
+}
+
+// Payloads smaller than this are sent raw even when compression is negotiated.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+
+// Why a frame was rejected. Carried inside the `io::Error` returned by `Codec::read`.
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge { len: usize, max: usize },
+    UnexpectedCompression, // Compressed frame on a connection that did not negotiate compression.
+    Decompress(DecompressError),
+    ChecksumMissing, // Checksums were negotiated but the frame has no trailer.
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl FrameError {
+    // Returns the frame error wrapped in `error`, if any.
+    pub fn from_io(error: &io::Error) -> Option<&FrameError> {
+        error.get_ref().and_then(|e| e.downcast_ref::<FrameError>())
+    }
+
+    // Corruption detected by the checksum. The whole frame was consumed, so the stream may still be in sync.
+    pub fn is_corruption(&self) -> bool {
+        matches!(self, FrameError::ChecksumMissing | FrameError::ChecksumMismatch { .. })
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the limit of {} bytes", len, max)
+            }
+            FrameError::UnexpectedCompression => write!(f, "compressed frame received without negotiated compression"),
+            FrameError::Decompress(e) => write!(f, "{}", e),
+            FrameError::ChecksumMissing => write!(f, "frame has no checksum trailer"),
+            FrameError::ChecksumMismatch { expected, actual } => {
+                write!(f, "frame checksum mismatch: trailer {:08x}, computed {:08x}", expected, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(error: FrameError) -> Self {
+        io::Error::new(ErrorKind::InvalidData, error)
+    }
+}
+
+// Frame reader/writer settings for one side of a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    pub max_len: usize,                   // Largest accepted payload, before and after decompression.
+    pub compression: CompressionAlgorithm, // `None` until compression is negotiated.
+    pub compression_threshold: usize,
+    pub checksums: bool, // Append a CRC32C trailer to written frames and require one on read frames.
+}
+
+impl Codec {
+    pub fn new(max_len: usize) -> Self {
+        Codec {
+            max_len,
+            compression: CompressionAlgorithm::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            checksums: false,
+        }
+    }
+
+    pub fn with_compression(mut self, compression: CompressionAlgorithm, threshold: usize) -> Self {
+        self.compression = compression;
+        self.compression_threshold = threshold;
+        self
+    }
+
+    pub fn with_checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W, payload: &[u8]) -> io::Result<()> {
+        let mut body = None;
+        if self.compression != CompressionAlgorithm::None && payload.len() >= self.compression_threshold {
+            let compressed = compression::compress(self.compression, payload);
+            if compressed.len() + HEADER_LEN < payload.len() {  // Only worth it if the frame shrinks.
+                let mut framed = Vec::with_capacity(HEADER_LEN + compressed.len());
+                framed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                framed.extend_from_slice(&compressed);
+                body = Some(framed);
+            }
+        }
+
+        let (mut flags, body) = match body {
+            Some(ref compressed) => (FLAG_COMPRESSED, &compressed[..]),
+            None => (0, payload),
+        };
+        if self.checksums {
+            flags |= FLAG_CHECKSUM;
+        }
+        let len = u32::try_from(body.len())
+            .ok()
+            .filter(|len| *len <= LENGTH_MASK)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "frame payload too large"))?;
+
+        let mut frame = Vec::with_capacity(HEADER_LEN + body.len() + TRAILER_LEN);  // Write the frame in one call.
+        frame.extend_from_slice(&(len | flags).to_be_bytes());
+        frame.extend_from_slice(body);
+        if self.checksums {
+            let crc = checksum(&frame[..HEADER_LEN], body);
+            frame.extend_from_slice(&crc.to_be_bytes());
+        }
+        writer.write_all(&frame)?;
+        writer.flush()
+    }
+
+    // Reads one frame. Returns `Ok(None)` if the peer closed the connection cleanly between frames,
+    // and an `InvalidData` error wrapping a `FrameError` if the frame is rejected.
+    pub fn read<R: Read>(&self, reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut filled = 0;
+        while filled < HEADER_LEN {
+            match reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed mid-frame")),
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let header_bytes = header;
+        let header = u32::from_be_bytes(header);
+        let len = (header & LENGTH_MASK) as usize;
+        if len > self.max_len {
+            return Err(FrameError::TooLarge { len, max: self.max_len }.into());
+        }
+
+        // Grown as bytes arrive rather than allocated from the header, so a sender that announces a
+        // large frame and stalls holds no more memory than it has actually sent.
+        let mut payload = Vec::new();
+        if reader.take(len as u64).read_to_end(&mut payload)? < len {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed mid-frame"));
+        }
+        if header & FLAG_CHECKSUM != 0 {
+            // Verified whenever present, even if this side did not ask for checksums.
+            let mut trailer = [0u8; TRAILER_LEN];
+            reader.read_exact(&mut trailer)?;
+            let expected = u32::from_be_bytes(trailer);
+            let actual = checksum(&header_bytes, &payload);
+            if expected != actual {
+                return Err(FrameError::ChecksumMismatch { expected, actual }.into());
+            }
+        } else if self.checksums {
+            return Err(FrameError::ChecksumMissing.into());
+        }
+
+        if header & FLAG_COMPRESSED == 0 {
+            return Ok(Some(payload));
+        }
+
+        if self.compression == CompressionAlgorithm::None {
+            return Err(FrameError::UnexpectedCompression.into());
+        }
+        if payload.len() < HEADER_LEN {
+            return Err(FrameError::Decompress(DecompressError("missing uncompressed length".to_string())).into());
+        }
+        let (original_len, compressed) = payload.split_at(HEADER_LEN);
+        let original_len = u32::from_be_bytes(original_len.try_into().unwrap()) as usize;
+        if original_len > self.max_len {
+            // Checked before inflating anything so a small frame cannot expand past the limit.
+            return Err(FrameError::TooLarge {
+                len: original_len,
+                max: self.max_len,
+            }
+            .into());
+        }
+        compression::decompress(self.compression, compressed, original_len)
+            .map(Some)
+            .map_err(|e| FrameError::Decompress(e).into())
+    }
+}
+
+// Writes an uncompressed frame.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    Codec::new(LENGTH_MASK as usize).write(writer, payload)
+}
+
+// Reads a frame from a connection without compression.
+pub fn read_frame<R: Read>(reader: &mut R, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+    Codec::new(max_len).read(reader)
+}
@@ -0,0 +1,216 @@
+use crate::auth;
+use crate::http::{self, Request, Response};
+use crate::message::{auth_request, client_message, server_message, AuthRequest, ErrorCode, ErrorResponse};
+use crate::policy::MessageKind;
+use crate::ratelimit::{self, Admission, Buckets, Limits};
+use crate::server::Shared;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use std::{
+    io::{self, ErrorKind, Read},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, info_span, warn};
+
+// How long a caller may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long, and for how many bytes, the request of a refused caller is read and discarded, so that
+// closing the connection does not reset it before the refusal arrives.
+const REFUSAL_DRAIN: Duration = Duration::from_millis(500);
+const REFUSAL_DRAIN_LEN: u64 = 64 * 1024;
+
+// HTTP status answering an `ErrorResponse` with `code`.
+pub fn status(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::InvalidArgument => 400,
+        ErrorCode::DivisionByZero | ErrorCode::Overflow => 422,
+        ErrorCode::LimitExceeded => 413,
+        ErrorCode::Unauthenticated | ErrorCode::CredentialsExpired => 401,
+        ErrorCode::PermissionDenied => 403,
+        ErrorCode::NotFound => 404,
+        ErrorCode::RateLimited => 429,
+        ErrorCode::TooManyConnections | ErrorCode::HandlerDisabled => 503,
+        // Only sent on framed connections.
+        ErrorCode::UnsupportedVersion | ErrorCode::HandshakeRequired | ErrorCode::NotNegotiated | ErrorCode::CorruptedFrame => 400,
+        ErrorCode::Unspecified => 500,
+    }
+}
+
+// Key of the request in the JSON encoding of `client_message::Message`.
+fn variant_key(kind: MessageKind) -> &'static str {
+    match kind {
+        MessageKind::Echo => "echo_message",
+        MessageKind::EchoRequest => "echo_request",
+        MessageKind::BinaryEcho => "binary_echo_request",
+        MessageKind::Add => "add_request",
+        MessageKind::Eval => "eval_request",
+        MessageKind::Float => "float_arithmetic_request",
+        MessageKind::Decimal => "decimal_arithmetic_request",
+        MessageKind::Batch => "batch_request",
+        MessageKind::Ping => "ping",
+    }
+}
+
+fn error(code: ErrorCode, message: impl Into<String>) -> Response {
+    let error = ErrorResponse {
+        code: code as i32,
+        message: message.into(),
+    };
+    Response::json(status(code), json!(error).to_string())
+}
+
+// HTTP front end to the request handlers, for dashboards and scripts. Each request type is served as
+// `POST /<kind>`, with `MessageKind` names as kinds and the request in the JSON encoding as body, e.g.
+// `POST /add` with `{"a":1,"b":2}` answers `{"result":3}`. Errors are answered as the `ErrorResponse`
+// with a matching status; see `status`. Requests pass the same checks as on a connection, and when
+// the server has credentials they authenticate with HTTP Basic, the token being the password.
+// Gateway connections count toward the connection limits. As every request stands alone, only the
+// identity and global rate limits apply to it, not the per-connection ones.
+pub(crate) struct Gateway {
+    listener: TcpListener,
+}
+
+impl Gateway {
+    pub(crate) fn bind(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Gateway { listener })
+    }
+
+    // Accepts requests until the server stops.
+    pub(crate) fn run(&self, shared: Arc<Shared>) {
+        info!("Gateway listening on {:?}", self.listener.local_addr());
+        while shared.is_running.load(Ordering::SeqCst) {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    let permit = shared.connections.acquire(addr.ip());  // Counted with the framed connections.
+                    let shared = shared.clone();
+                    thread::spawn(move || {
+                        let _entered = info_span!("gateway", peer = %addr).entered();
+                        let result = match permit {
+                            Ok(_permit) => serve(stream, addr, &shared),
+                            Err(reason) => {
+                                warn!("Refusing gateway connection from {}: {}", addr, reason);
+                                refuse(stream, reason)
+                            }
+                        };
+                        if let Err(e) = result {
+                            warn!("Failed to answer gateway request: {}", e);
+                        }
+                    });
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(10)),
+                Err(e) => error!("Error accepting gateway connection: {}", e),
+            }
+        }
+    }
+}
+
+// Answers a connection over the connection limits without handling its request.
+fn refuse(mut stream: TcpStream, reason: String) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    error(ErrorCode::TooManyConnections, reason).write_to(&mut stream)?;
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_read_timeout(Some(REFUSAL_DRAIN))?;
+    let _ = io::copy(&mut (&stream).take(REFUSAL_DRAIN_LEN), &mut io::sink());
+    Ok(())
+}
+
+fn serve(mut stream: TcpStream, peer: SocketAddr, shared: &Shared) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let max_body = shared.config().max_frame_size;
+    let response = match http::read_request(&mut stream, max_body) {
+        Ok(request) => handle(&request, peer, shared),
+        Err(response) => response,
+    };
+    response.write_to(&mut stream)
+}
+
+// The identity of the caller, if the server asks for credentials.
+fn authenticate(request: &Request, shared: &Shared) -> Result<Option<String>, Response> {
+    let config = shared.config();
+    let Some(ref credentials) = config.credentials else {
+        return Ok(None);
+    };
+    let challenge = format!("Basic realm=\"{}\"", config.server_name);
+    let basic = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let Some((identity, token)) = basic.as_deref().and_then(|basic| basic.split_once(':')) else {
+        return Err(error(ErrorCode::Unauthenticated, "authentication required").with_header("WWW-Authenticate", challenge));
+    };
+    let attempt = AuthRequest {
+        identity: identity.to_string(),
+        credential: Some(auth_request::Credential::Token(token.to_string())),
+    };
+    match auth::verify(credentials.as_ref(), &attempt, None) {
+        Ok(()) => Ok(Some(attempt.identity)),
+        Err(e) => {
+            warn!("Rejecting gateway credentials for '{}': {}", identity, e.message);
+            let code = ErrorCode::try_from(e.code).unwrap_or(ErrorCode::Unauthenticated);
+            Err(error(code, e.message).with_header("WWW-Authenticate", challenge))
+        }
+    }
+}
+
+fn handle(request: &Request, peer: SocketAddr, shared: &Shared) -> Response {
+    let Some(kind) = MessageKind::parse(request.path.trim_start_matches('/')) else {
+        return error(ErrorCode::NotFound, format!("no handler at {}", request.path));
+    };
+    if request.method != "POST" {
+        let response = error(ErrorCode::InvalidArgument, "only POST is supported").with_header("Allow", "POST");
+        return Response { status: 405, ..response };
+    }
+    if request.header("Content-Type").is_some_and(|content_type| !content_type.starts_with("application/json")) {
+        return Response { status: 415, ..error(ErrorCode::InvalidArgument, "the body must be application/json") };
+    }
+    let identity = match authenticate(request, shared) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    let body = if request.body.is_empty() { Ok(json!({})) } else { serde_json::from_slice::<Value>(&request.body) };
+    let message = body.and_then(|body| serde_json::from_value::<client_message::Message>(json!({ variant_key(kind): body })));
+    let message = match message {
+        Ok(message) => message,
+        Err(e) => return error(ErrorCode::InvalidArgument, format!("invalid {} request: {}", kind.name(), e)),
+    };
+
+    let config = shared.config();
+    let mut buckets = Buckets::new(&Limits::default());  // Unlimited: only the identity and global limits apply.
+    let requests = ratelimit::requests(Some(&message));
+    match shared.rate_limiter.admit(&mut buckets, identity.as_deref(), requests, request.body.len()) {
+        Admission::Allowed => {}
+        Admission::Delayed(wait) => thread::sleep(wait),
+        Admission::Exceeded(scope) => return error(ErrorCode::RateLimited, format!("{} rate limit exceeded", scope)),
+    }
+    debug!("Gateway request for {}", kind.name());
+    shared.metrics.received(request.body.len());
+
+    let started = Instant::now();
+    let response = match shared.dispatch(&config, identity.as_deref(), Some(peer), message) {
+        server_message::Message::Error(e) => {
+            let code = ErrorCode::try_from(e.code).unwrap_or(ErrorCode::Unspecified);
+            Response::json(status(code), json!(e).to_string())
+        }
+        response => {
+            // Encoded as `{"add_response":{"result":3}}`: answer with the inner object.
+            let body = match json!(response) {
+                Value::Object(map) => map.into_iter().next().map(|(_, value)| value).unwrap_or_default(),
+                other => other,
+            };
+            Response::json(200, body.to_string())
+        }
+    };
+    shared.metrics.request(kind.name(), started.elapsed());
+    shared.metrics.sent(response.body.len());
+    response
+}
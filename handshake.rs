@@ -0,0 +1,76 @@
+use crate::config::ServerConfig;
+use crate::message::{Capability, CompressionAlgorithm, ErrorCode, ErrorResponse, Hello, Welcome};
+
+// Version spoken by this build, and the oldest version it still accepts.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Features in effect for one connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    pub protocol_version: u32,
+    pub request_ids: bool,
+    pub batching: bool,
+    pub compression: CompressionAlgorithm, // `None` unless compression was negotiated.
+    pub checksums: bool,
+}
+
+impl Features {
+    // What a connection gets without a handshake: the original protocol, which already allowed batching.
+    pub fn legacy() -> Self {
+        Features {
+            protocol_version: MIN_PROTOCOL_VERSION,
+            request_ids: false,
+            batching: true,
+            compression: CompressionAlgorithm::None,
+            checksums: false,
+        }
+    }
+}
+
+// Answers a Hello. Both sides speak the lower of their two versions, and only capabilities
+// requested by the client and enabled on the server are granted. Compression is granted only if
+// the client lists an algorithm the server allows; the client's first such choice wins.
+pub fn negotiate(hello: &Hello, config: &ServerConfig) -> Result<(Welcome, Features), ErrorResponse> {
+    if hello.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(ErrorResponse {
+            code: ErrorCode::UnsupportedVersion as i32,
+            message: format!(
+                "protocol version {} is not supported (supported: {}..={})",
+                hello.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        });
+    }
+    let protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+
+    let mut granted: Vec<Capability> = hello
+        .capabilities()
+        .filter(|capability| config.capabilities.contains(capability))
+        .collect();
+    granted.sort_by_key(|capability| *capability as i32);
+    granted.dedup();
+
+    let compression = hello
+        .compression()
+        .find(|algorithm| *algorithm != CompressionAlgorithm::None && config.compression.contains(algorithm))
+        .filter(|_| granted.contains(&Capability::Compression))
+        .unwrap_or(CompressionAlgorithm::None);
+    if compression == CompressionAlgorithm::None {
+        granted.retain(|capability| *capability != Capability::Compression);
+    }
+
+    let welcome = Welcome {
+        protocol_version,
+        server_name: config.server_name.clone(),
+        capabilities: granted.iter().map(|capability| *capability as i32).collect(),
+        compression: compression as i32,
+    };
+    let features = Features {
+        protocol_version,
+        request_ids: granted.contains(&Capability::RequestIds),
+        batching: granted.contains(&Capability::Batching),
+        compression,
+        checksums: granted.contains(&Capability::Checksums),
+    };
+    Ok((welcome, features))
+}
@@ -0,0 +1,207 @@
+use crate::server::Shared;
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+// Largest request head accepted.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+// How long a scraper may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub headers: Vec<(&'static str, String)>, // Sent in addition to the content and connection headers.
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn json(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, "application/json", body)
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            411 => "Length Required",
+            413 => "Content Too Large",
+            415 => "Unsupported Media Type",
+            422 => "Unprocessable Content",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+
+    // Writes the response and tells the client the connection closes after it.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\n", self.status, self.reason(), self.content_type);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len()));
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(self.body.as_bytes())?;
+        writer.flush()
+    }
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String, // Without the query string.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    // The first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+// Reads a request and its body, which must be sent with a Content-Length of at most `max_body` bytes.
+pub fn read_request<R: Read>(reader: &mut R, max_body: usize) -> Result<Request, Response> {
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 1024];
+    let head_len = loop {
+        if let Some(end) = received.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if received.len() > MAX_HEAD_LEN {
+            return Err(Response::text(431, "request head too large\n"));
+        }
+        match reader.read(&mut buffer) {
+            Ok(0) => return Err(Response::text(400, "incomplete request\n")),
+            Ok(read) => received.extend_from_slice(&buffer[..read]),
+            Err(e) => return Err(Response::text(400, format!("failed to read request: {}\n", e))),
+        }
+    };
+    let mut body = received.split_off(head_len);
+    let head = String::from_utf8_lossy(&received);
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = match (request_line.next(), request_line.next(), request_line.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target.split('?').next().unwrap_or_default().to_string())
+        }
+        _ => return Err(Response::text(400, "malformed request line\n")),
+    };
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut request = Request { method, path, headers, body: Vec::new() };
+
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(Response::text(411, "only bodies with a Content-Length are supported\n"));
+    }
+    let len = match request.header("Content-Length").map(str::parse::<usize>) {
+        None => 0,
+        Some(Ok(len)) if len <= max_body => len,
+        Some(Ok(_)) => return Err(Response::text(413, format!("request body over {} bytes\n", max_body))),
+        Some(Err(_)) => return Err(Response::text(400, "invalid Content-Length\n")),
+    };
+    body.truncate(len);
+    if body.len() < len {
+        let mut rest = vec![0u8; len - body.len()];
+        reader.read_exact(&mut rest).map_err(|e| Response::text(400, format!("failed to read request body: {}\n", e)))?;
+        body.extend_from_slice(&rest);
+    }
+    request.body = body;
+    Ok(request)
+}
+
+// Answers a health check with 200 when `status` is `Ok` and 503 with the reason otherwise.
+fn check(status: Result<(), String>) -> Response {
+    match status {
+        Ok(()) => Response::text(200, "ok\n"),
+        Err(reason) => Response::text(503, format!("{}\n", reason)),
+    }
+}
+
+fn route(method: &str, path: &str, shared: &Shared) -> Response {
+    match (method, path) {
+        ("GET", "/metrics") => Response::new(200, "text/plain; version=0.0.4; charset=utf-8", shared.render_metrics()),
+        ("GET", "/healthz") => check(shared.liveness()),
+        ("GET", "/readyz") => check(shared.readiness()),
+        (_, "/metrics" | "/healthz" | "/readyz") => Response::text(405, "only GET is supported\n"),
+        _ => Response::text(404, "not found\n"),
+    }
+}
+
+// The built-in HTTP endpoint of a server, serving one request per connection. It runs for the
+// whole life of the `Server`, so health checks can tell a server that has not started or has
+// stopped from one that is gone.
+pub(crate) struct HttpServer {
+    listener: TcpListener,
+}
+
+impl HttpServer {
+    pub(crate) fn bind(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(HttpServer { listener })
+    }
+
+    // Accepts requests until the server is dropped.
+    pub(crate) fn run(&self, shared: Arc<Shared>) {
+        while !shared.released.load(Ordering::SeqCst) {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("HTTP request from {}", addr);
+                    let shared = shared.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, &shared) {
+                            warn!("Failed to answer HTTP request from {}: {}", addr, e);
+                        }
+                    });
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(10)),
+                Err(e) => error!("Error accepting HTTP connection: {}", e),
+            }
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let response = match read_request(&mut stream, 0) {  // The built-in endpoints take no body.
+        Ok(request) => route(&request.method, &request.path, shared),
+        Err(response) => response,
+    };
+    response.write_to(&mut stream)
+}
@@ -1,4 +1,27 @@
+pub mod admin;
+pub mod arithmetic;
+pub mod audit;
+pub mod auth;
+pub mod capture;
+pub mod compression;
+pub mod config;
+pub mod connections;
+pub mod deadline;
+pub mod echo;
+pub mod encoding;
+pub mod eval;
+pub mod frame;
+pub mod gateway;
+pub mod handshake;
+pub mod http;
+pub mod logging;
+pub mod metrics;
+pub mod policy;
+pub mod ratelimit;
+pub mod registry;
 pub mod server;
+pub mod tls;
+pub mod websocket;
 
 pub mod message {
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
@@ -0,0 +1,310 @@
+use crate::message::{auth_request, client_message::Message, ClientMessage};
+use crate::metrics;
+use prost::Message as _;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    io,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tracing::Subscriber;
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::{self, MakeWriter},
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
+
+// How events are written by the subscriber from `init`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text, // One line per event, prefixed with the enclosing spans, e.g. `connection{peer=… id=3}:request{…}`.
+    Json, // One JSON object per event, with the fields of the current span and of every enclosing span.
+}
+
+// Level filter of the subscriber installed by `init`, changed through `set_level`.
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+fn tracing_level(level: log::LevelFilter) -> LevelFilter {
+    match level {
+        log::LevelFilter::Off => LevelFilter::OFF,
+        log::LevelFilter::Error => LevelFilter::ERROR,
+        log::LevelFilter::Warn => LevelFilter::WARN,
+        log::LevelFilter::Info => LevelFilter::INFO,
+        log::LevelFilter::Debug => LevelFilter::DEBUG,
+        log::LevelFilter::Trace => LevelFilter::TRACE,
+    }
+}
+
+fn build<W>(format: LogFormat, level: log::LevelFilter, writer: W) -> (impl Subscriber + Send + Sync, reload::Handle<LevelFilter, Registry>)
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let (filter, handle) = reload::Layer::new(tracing_level(level));
+    let output = match format {
+        LogFormat::Text => fmt::layer().with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer().json().with_current_span(true).with_span_list(true).with_writer(writer).boxed(),
+    };
+    (tracing_subscriber::registry().with(filter).with(output), handle)
+}
+
+// Builds the subscriber `init` installs, writing to `writer` instead of stderr. Its level is fixed.
+pub fn subscriber<W>(format: LogFormat, level: log::LevelFilter, writer: W) -> impl Subscriber + Send + Sync
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    build(format, level, writer).0
+}
+
+// Installs a global subscriber writing to stderr and routes `log` records, e.g. from rustls, into it.
+// Without it, the server's events are still emitted as `log` records for an application's own logger.
+// Fails if a subscriber or logger is already installed.
+pub fn init(format: LogFormat, level: log::LevelFilter) -> io::Result<()> {
+    let (subscriber, handle) = build(format, level, io::stderr);
+    subscriber.try_init().map_err(io::Error::other)?;
+    let _ = LEVEL.set(handle);
+    Ok(())
+}
+
+// Changes the level at runtime, for the `log` facade and for the subscriber installed by `init`.
+pub fn set_level(level: log::LevelFilter) {
+    log::set_max_level(level);
+    if let Some(handle) = LEVEL.get() {
+        let _ = handle.modify(|filter| *filter = tracing_level(level));
+    }
+}
+
+pub fn level() -> log::LevelFilter {
+    log::max_level()
+}
+
+// Replaces redacted strings and byte fields in logged requests.
+pub const REDACTED: &str = "[redacted]";
+
+// How the payload of each decoded request is logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadLogging {
+    Off,              // Only the message type and encoded size.
+    Truncated(usize), // The debug form, cut to at most this many bytes.
+    Hashed,           // The message type and a SHA-256 of the redacted request, to correlate identical requests.
+    Full,             // The whole debug form, logged at trace level instead of info.
+}
+
+// Hides one field of one message type in logged requests, e.g. `Redaction::new("echo", "content")`.
+// `message` is a type label from `metrics::VARIANTS`. Strings and bytes are replaced with `REDACTED`,
+// maps are emptied and numbers are zeroed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redaction {
+    pub message: String,
+    pub field: String,
+}
+
+impl Redaction {
+    pub fn new(message: impl Into<String>, field: impl Into<String>) -> Self {
+        Redaction {
+            message: message.into(),
+            field: field.into(),
+        }
+    }
+}
+
+// What the server logs about each request. Credentials in AuthRequests are always redacted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    pub payloads: PayloadLogging,
+    pub redactions: Vec<Redaction>,
+    pub max_request_logs_per_sec: Option<u32>, // Server-wide; lines over the budget are counted, not written.
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            payloads: PayloadLogging::Truncated(256),
+            redactions: Vec::new(),
+            max_request_logs_per_sec: None,
+        }
+    }
+}
+
+// Blanks `field` of `message`. Fields the message type does not have are left alone.
+fn redact_field(message: &mut Message, field: &str) {
+    let text = || REDACTED.to_string();
+    let bytes = || REDACTED.as_bytes().to_vec();
+    match (message, field) {
+        (Message::EchoMessage(echo), "content") => echo.content = text(),
+        (Message::EchoRequest(echo), "content") => echo.content = text(),
+        (Message::EchoRequest(echo), "pad_char") => echo.pad_char = text(),
+        (Message::BinaryEchoRequest(echo), "payload") => echo.payload = bytes(),
+        (Message::AddRequest(add), "a") => add.a = 0,
+        (Message::AddRequest(add), "b") => add.b = 0,
+        (Message::EvalRequest(eval), "expression") => eval.expression = text(),
+        (Message::EvalRequest(eval), "variables") => eval.variables.clear(),
+        (Message::FloatArithmeticRequest(float), "a") => float.a = 0.0,
+        (Message::FloatArithmeticRequest(float), "b") => float.b = 0.0,
+        (Message::DecimalArithmeticRequest(decimal), "a") => decimal.a = None,
+        (Message::DecimalArithmeticRequest(decimal), "b") => decimal.b = None,
+        (Message::Ping(ping), "nonce") => ping.nonce = 0,
+        (Message::Hello(hello), "client_name") => hello.client_name = text(),
+        (Message::AuthRequest(auth), "identity") => auth.identity = text(),
+        _ => {}
+    }
+}
+
+impl LogConfig {
+    // Applies the redaction rules to `message` and the entries of a batch.
+    fn redact_message(&self, message: &mut Message) {
+        let variant = metrics::variant(message);
+        for redaction in self.redactions.iter().filter(|redaction| redaction.message == variant) {
+            redact_field(message, &redaction.field);
+        }
+        match message {
+            Message::AuthRequest(auth) => {
+                auth.credential = match auth.credential.take() {
+                    Some(auth_request::Credential::Token(_)) => Some(auth_request::Credential::Token(REDACTED.to_string())),
+                    Some(auth_request::Credential::Signature(_)) => {
+                        Some(auth_request::Credential::Signature(REDACTED.as_bytes().to_vec()))
+                    }
+                    other => other,
+                };
+            }
+            Message::BatchRequest(batch) => {
+                for entry in batch.messages.iter_mut().filter_map(|entry| entry.message.as_mut()) {
+                    self.redact_message(entry);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Whether `message`, or an entry of a batch, has fields that `redact_message` changes.
+    fn redacts(&self, message: &Message) -> bool {
+        let variant = metrics::variant(message);
+        self.redactions.iter().any(|redaction| redaction.message == variant)
+            || match message {
+                Message::AuthRequest(_) => true,
+                Message::BatchRequest(batch) => batch.messages.iter().filter_map(|entry| entry.message.as_ref()).any(|entry| self.redacts(entry)),
+                _ => false,
+            }
+    }
+
+    // `request` as it is safe to log, copied only when something in it must be redacted.
+    pub fn redact<'a>(&self, request: &'a ClientMessage) -> Cow<'a, ClientMessage> {
+        match request.message {
+            Some(ref message) if self.redacts(message) => {
+                let mut request = request.clone();
+                if let Some(ref mut message) = request.message {
+                    self.redact_message(message);
+                }
+                Cow::Owned(request)
+            }
+            _ => Cow::Borrowed(request),
+        }
+    }
+
+    // The request as it should appear in the log, according to `payloads`.
+    pub fn describe(&self, request: &ClientMessage) -> String {
+        let variant = request.message.as_ref().map_or("-", metrics::variant);
+        match self.payloads {
+            PayloadLogging::Off => format!("{} ({} bytes)", variant, request.encoded_len()),
+            PayloadLogging::Truncated(limit) => {
+                // Formatting stops at the limit, so a large payload costs no more to log than a small one.
+                let mut text = Bounded { text: String::new(), limit };
+                if write!(text, "{:?}", self.redact(request)).is_err() {
+                    let _ = write!(text.text, "... ({} bytes in total)", request.encoded_len());
+                }
+                text.text
+            }
+            PayloadLogging::Hashed => {
+                let digest = Sha256::digest(self.redact(request).encode_to_vec());  // Never a hash of a credential.
+                let hex = digest.iter().fold(String::new(), |mut hex, byte| {
+                    let _ = write!(hex, "{:02x}", byte);
+                    hex
+                });
+                format!("{} sha256:{}", variant, hex)
+            }
+            PayloadLogging::Full => format!("{:?}", self.redact(request)),
+        }
+    }
+}
+
+// `request` with only the credentials of its AuthRequests, batch entries included, replaced by `REDACTED`;
+// copied only if it has any. For records other than logs that must not hold secrets, such as captures.
+pub fn redact_credentials(request: &ClientMessage) -> Cow<'_, ClientMessage> {
+    LogConfig::default().redact(request)  // No redaction rules, so only the credentials are touched.
+}
+
+// Collects formatted text up to `limit` bytes, then fails so the formatting stops.
+struct Bounded {
+    text: String,
+    limit: usize,
+}
+
+impl Write for Bounded {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let room = self.limit - self.text.len();
+        if s.len() <= room {
+            self.text.push_str(s);
+            return Ok(());
+        }
+        let end = (0..=room).rev().find(|end| s.is_char_boundary(*end)).unwrap_or(0);
+        self.text.push_str(&s[..end]);
+        Err(std::fmt::Error)
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+// Lets at most `per_second` lines through in each one-second window and counts the rest.
+#[derive(Debug)]
+pub struct Sampler {
+    per_second: Option<u32>,
+    window: Mutex<Window>,
+}
+
+impl Sampler {
+    pub fn new(per_second: Option<u32>) -> Self {
+        Sampler {
+            per_second,
+            window: Mutex::new(Window {
+                started: Instant::now(),
+                logged: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    // Whether the next line may be written, and how many lines the window that just ended
+    // suppressed. That count is returned once, so it can be logged in place of those lines.
+    pub fn admit(&self) -> (bool, u64) {
+        let Some(per_second) = self.per_second else {
+            return (true, 0);
+        };
+        let mut window = self.window.lock().unwrap();
+        let mut suppressed = 0;
+        if window.started.elapsed() >= Duration::from_secs(1) {
+            suppressed = window.suppressed;
+            *window = Window {
+                started: Instant::now(),
+                logged: 0,
+                suppressed: 0,
+            };
+        }
+        if window.logged < per_second {
+            window.logged += 1;
+            (true, suppressed)
+        } else {
+            window.suppressed += 1;
+            (false, suppressed)
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use crate::message::client_message::Message;
+use crate::policy::MessageKind;
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Message types tracked per variant: every policy kind plus the two connection setup messages.
+pub const VARIANTS: [&str; 11] = [
+    "echo",
+    "echo-request",
+    "binary-echo",
+    "add",
+    "eval",
+    "float",
+    "decimal",
+    "batch",
+    "ping",
+    "hello",
+    "auth",
+];
+
+// Upper bounds of the latency histogram buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 10.0,
+];
+
+// Label of a message in the per-variant metrics.
+pub fn variant(message: &Message) -> &'static str {
+    match message {
+        Message::Hello(_) => "hello",
+        Message::AuthRequest(_) => "auth",
+        message => MessageKind::of(message).map_or("-", MessageKind::name),
+    }
+}
+
+fn index(variant: &str) -> Option<usize> {
+    VARIANTS.iter().position(|name| *name == variant)
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1], // Not cumulative; the last one is +Inf.
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Figures owned by other parts of the server, read when the metrics are rendered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerGauges {
+    pub accepted_connections: u64,
+    pub rejected_connections: u64,
+    pub open_connections: u64,
+    pub max_connections: Option<usize>, // Size of the connection pool; `None` is unlimited.
+    pub corrupted_frames: u64,
+}
+
+// Counters and latency histograms collected by a server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    received: [AtomicU64; VARIANTS.len()],
+    latency: [Histogram; VARIANTS.len()],
+    decode_errors: AtomicU64,
+    frame_errors: AtomicU64, // Frames rejected for their size or encoding, which closes the connection.
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages_out: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn frame_error(&self) {
+        self.frame_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Records one answered request of type `variant` and the time taken to compute its response.
+    pub fn request(&self, variant: &str, elapsed: Duration) {
+        if let Some(index) = index(variant) {
+            self.received[index].fetch_add(1, Ordering::Relaxed);
+            self.latency[index].observe(elapsed);
+        }
+    }
+
+    // Requests of type `variant` answered so far.
+    pub fn requests(&self, variant: &str) -> u64 {
+        index(variant).map_or(0, |index| self.received[index].load(Ordering::Relaxed))
+    }
+
+    // Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self, gauges: &ServerGauges) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut single = vec![
+            ("server_connections_accepted_total", "counter", "Connections accepted, refused ones included.", gauges.accepted_connections),
+            ("server_connections_rejected_total", "counter", "Connections refused over the connection limits.", gauges.rejected_connections),
+            ("server_connections_active", "gauge", "Connections currently being served.", gauges.open_connections),
+            ("server_decode_errors_total", "counter", "Frames that did not decode as a ClientMessage.", load(&self.decode_errors)),
+            ("server_frame_errors_total", "counter", "Frames rejected for their size or encoding.", load(&self.frame_errors)),
+            ("server_corrupted_frames_total", "counter", "Frames that failed their checksum.", gauges.corrupted_frames),
+            ("server_received_bytes_total", "counter", "Request payload bytes, after decompression.", load(&self.bytes_in)),
+            ("server_sent_bytes_total", "counter", "Response payload bytes, before compression.", load(&self.bytes_out)),
+            ("server_messages_sent_total", "counter", "Responses sent.", load(&self.messages_out)),
+        ];
+        if let Some(max) = gauges.max_connections {
+            single.push(("server_connections_max", "gauge", "Connections the server serves at once.", max as u64));
+        }
+
+        let mut out = String::new();
+        for (name, kind, help, value) in single {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+        }
+
+        let _ = writeln!(out, "# HELP server_messages_received_total Requests answered, by message type.");
+        let _ = writeln!(out, "# TYPE server_messages_received_total counter");
+        for (variant, received) in VARIANTS.iter().zip(&self.received) {
+            let _ = writeln!(out, "server_messages_received_total{{type=\"{}\"}} {}", variant, load(received));
+        }
+
+        let name = "server_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time taken to compute responses, by message type.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (variant, histogram) in VARIANTS.iter().zip(&self.latency) {
+            let mut cumulative = 0;
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += load(bucket);
+                let _ = writeln!(out, "{}_bucket{{type=\"{}\",le=\"{}\"}} {}", name, variant, bound, cumulative);
+            }
+            cumulative += load(&histogram.buckets[LATENCY_BUCKETS.len()]);
+            let _ = writeln!(out, "{}_bucket{{type=\"{}\",le=\"+Inf\"}} {}", name, variant, cumulative);
+            let sum = load(&histogram.sum_micros) as f64 / 1e6;
+            let _ = writeln!(out, "{}_sum{{type=\"{}\"}} {}", name, variant, sum);
+            let _ = writeln!(out, "{}_count{{type=\"{}\"}} {}", name, variant, load(&histogram.count));
+        }
+        out
+    }
+}
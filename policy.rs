@@ -0,0 +1,228 @@
+use crate::message::client_message::Message;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+};
+
+// Request types a policy can allow. Hello and AuthRequest set up the connection and are never restricted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Echo,
+    EchoRequest,
+    BinaryEcho,
+    Add,
+    Eval,
+    Float,
+    Decimal,
+    Batch,
+    Ping,
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 9] = [
+        MessageKind::Echo,
+        MessageKind::EchoRequest,
+        MessageKind::BinaryEcho,
+        MessageKind::Add,
+        MessageKind::Eval,
+        MessageKind::Float,
+        MessageKind::Decimal,
+        MessageKind::Batch,
+        MessageKind::Ping,
+    ];
+
+    pub fn of(message: &Message) -> Option<Self> {
+        Some(match message {
+            Message::EchoMessage(_) => MessageKind::Echo,
+            Message::EchoRequest(_) => MessageKind::EchoRequest,
+            Message::BinaryEchoRequest(_) => MessageKind::BinaryEcho,
+            Message::AddRequest(_) => MessageKind::Add,
+            Message::EvalRequest(_) => MessageKind::Eval,
+            Message::FloatArithmeticRequest(_) => MessageKind::Float,
+            Message::DecimalArithmeticRequest(_) => MessageKind::Decimal,
+            Message::BatchRequest(_) => MessageKind::Batch,
+            Message::Ping(_) => MessageKind::Ping,
+            Message::Hello(_) | Message::AuthRequest(_) => return None,
+        })
+    }
+
+    // Name used in policy files and audit records.
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageKind::Echo => "echo",
+            MessageKind::EchoRequest => "echo-request",
+            MessageKind::BinaryEcho => "binary-echo",
+            MessageKind::Add => "add",
+            MessageKind::Eval => "eval",
+            MessageKind::Float => "float",
+            MessageKind::Decimal => "decimal",
+            MessageKind::Batch => "batch",
+            MessageKind::Ping => "ping",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+// What one role may send.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rule {
+    pub allow: HashSet<MessageKind>,
+    pub max_request_size: Option<usize>, // Largest encoded request in bytes, batches included.
+    pub max_batch_size: Option<usize>,   // Tighter than `ServerConfig::max_batch_size` for this role.
+}
+
+impl Rule {
+    pub fn allowing(kinds: impl IntoIterator<Item = MessageKind>) -> Self {
+        Rule {
+            allow: kinds.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_request_size(mut self, max_request_size: usize) -> Self {
+        self.max_request_size = Some(max_request_size);
+        self
+    }
+
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = Some(max_batch_size);
+        self
+    }
+
+    fn check(&self, message: &Message) -> Result<(), String> {
+        let Some(kind) = MessageKind::of(message) else {
+            return Ok(());
+        };
+        if !self.allow.contains(&kind) {
+            return Err(format!("{} is not allowed", kind.name()));
+        }
+        if let Some(max) = self.max_request_size {
+            let size = message.encoded_len();
+            if size > max {
+                return Err(format!("{} of {} bytes exceeds the limit of {}", kind.name(), size, max));
+            }
+        }
+        if let Message::BatchRequest(batch) = message {
+            if let Some(max) = self.max_batch_size.filter(|max| batch.messages.len() > *max) {
+                return Err(format!("batch of {} entries exceeds the limit of {}", batch.messages.len(), max));
+            }
+            for entry in batch.messages.iter().filter_map(|entry| entry.message.as_ref()) {
+                self.check(entry).map_err(|reason| format!("batch entry: {}", reason))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Maps identities to roles and roles to rules. Identities without a role of their own, and
+// connections that never authenticated, get the default role; without one they may send nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    roles: HashMap<String, Rule>,
+    identities: HashMap<String, String>,
+    default_role: Option<String>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_role(mut self, role: impl Into<String>, rule: Rule) -> Self {
+        self.roles.insert(role.into(), rule);
+        self
+    }
+
+    pub fn with_identity(mut self, identity: impl Into<String>, role: impl Into<String>) -> Self {
+        self.identities.insert(identity.into(), role.into());
+        self
+    }
+
+    pub fn with_default_role(mut self, role: impl Into<String>) -> Self {
+        self.default_role = Some(role.into());
+        self
+    }
+
+    // Reads a policy file made of these lines (blank lines and `#` comments are ignored):
+    //
+    //   role <name> <kind|*>... [max-request=<bytes>] [max-batch=<entries>]
+    //   identity <identity|*> <role>
+    //
+    // where `*` as a kind allows every kind and `*` as an identity sets the default role.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let invalid = |line: usize, message: String| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), line, message))
+        };
+
+        let mut policy = Policy::new();
+        let mut assignments = Vec::new();
+        let contents = fs::read_to_string(path)?;
+        for (index, line) in contents.lines().enumerate() {
+            let line_number = index + 1;
+            let fields: Vec<&str> = line.split('#').next().unwrap_or_default().split_whitespace().collect();
+            match fields[..] {
+                [] => {}
+                ["role", name, ref entries @ ..] => {
+                    let mut rule = Rule::default();
+                    for entry in entries {
+                        let limit = |value: &str| {
+                            value.parse().map_err(|_| invalid(line_number, format!("invalid limit `{}`", entry)))
+                        };
+                        match entry.split_once('=') {
+                            Some(("max-request", value)) => rule.max_request_size = Some(limit(value)?),
+                            Some(("max-batch", value)) => rule.max_batch_size = Some(limit(value)?),
+                            Some(_) => return Err(invalid(line_number, format!("unknown limit `{}`", entry))),
+                            None if *entry == "*" => rule.allow.extend(MessageKind::ALL),
+                            None => {
+                                let kind = MessageKind::parse(entry)
+                                    .ok_or_else(|| invalid(line_number, format!("unknown message kind `{}`", entry)))?;
+                                rule.allow.insert(kind);
+                            }
+                        }
+                    }
+                    if policy.roles.insert(name.to_string(), rule).is_some() {
+                        return Err(invalid(line_number, format!("role `{}` is defined twice", name)));
+                    }
+                }
+                ["identity", identity, role] => assignments.push((line_number, identity, role.to_string())),
+                _ => return Err(invalid(line_number, "expected a `role` or `identity` line".to_string())),
+            }
+        }
+
+        // Roles may be assigned before they are defined.
+        for (line_number, identity, role) in assignments {
+            if !policy.roles.contains_key(&role) {
+                return Err(invalid(line_number, format!("unknown role `{}`", role)));
+            }
+            if identity == "*" {
+                policy.default_role = Some(role);
+            } else {
+                policy.identities.insert(identity.to_string(), role);
+            }
+        }
+        Ok(policy)
+    }
+
+    // Role applied to `identity`, which is `None` for connections that did not authenticate.
+    pub fn role(&self, identity: Option<&str>) -> Option<&str> {
+        identity
+            .and_then(|identity| self.identities.get(identity))
+            .or(self.default_role.as_ref())
+            .map(String::as_str)
+    }
+
+    // Decides whether `identity` may send `message`. The error explains the denial.
+    pub fn check(&self, identity: Option<&str>, message: &Message) -> Result<(), String> {
+        if MessageKind::of(message).is_none() {
+            return Ok(());
+        }
+        let role = self.role(identity).ok_or_else(|| "no role is assigned".to_string())?;
+        let rule = self.roles.get(role).ok_or_else(|| format!("role '{}' is not defined", role))?;
+        rule.check(message).map_err(|reason| format!("role '{}': {}", role, reason))
+    }
+}
@@ -0,0 +1,238 @@
+use crate::message::client_message;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Sustained `rate` per second, with bursts of up to `burst` above it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimit { rate, burst }
+    }
+
+    // Fails for limits no bucket can enforce: a negative or non-finite rate, or a burst below one token.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.rate.is_finite() && self.rate >= 0.0) {
+            return Err(format!("rate {} must be finite and not negative", self.rate));
+        }
+        if !(self.burst.is_finite() && self.burst >= 1.0) {
+            return Err(format!("burst {} must be finite and at least 1", self.burst));
+        }
+        Ok(())
+    }
+}
+
+// Request and byte limits for one scope; `None` leaves that dimension unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub requests: Option<RateLimit>,
+    pub bytes: Option<RateLimit>, // Counted on decoded request payloads.
+}
+
+impl Limits {
+    pub fn is_limited(&self) -> bool {
+        self.requests.is_some() || self.bytes.is_some()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, limit) in [("requests", self.requests), ("bytes", self.bytes)] {
+            if let Some(limit) = limit {
+                limit.validate().map_err(|e| format!("{}: {}", name, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// What happens to a request over the limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExcessAction {
+    #[default]
+    Delay,      // Hold the request until the buckets allow it.
+    Reject,     // Answer with a RateLimited error and keep the connection.
+    Disconnect, // Answer with a RateLimited error and close the connection.
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimits {
+    pub per_connection: Limits, // Not applied to gateway requests, which have no lasting connection.
+    pub per_identity: Limits, // Shared by every connection authenticated as the same identity.
+    pub global: Limits,
+    pub on_excess: ExcessAction,
+}
+
+impl RateLimits {
+    // Checked when a server is created, so a mistake in the limits fails loudly instead of refusing every request.
+    pub fn validate(&self) -> Result<(), String> {
+        for (scope, limits) in [("per_connection", self.per_connection), ("per_identity", self.per_identity), ("global", self.global)] {
+            limits.validate().map_err(|e| format!("{} {}", scope, e))?;
+        }
+        Ok(())
+    }
+}
+
+// Token bucket that may go into debt, so a delayed request reserves its tokens up front.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.updated = now;
+    }
+
+    // Requests larger than the burst are admitted from a full bucket rather than never.
+    fn available(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= amount.min(self.limit.burst)
+    }
+
+    fn take(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= amount;
+    }
+
+    // Time until the bucket would be out of debt after taking `amount`, or `None` if it never would,
+    // as at a rate of zero.
+    fn wait(&mut self, amount: f64, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        let debt = amount - self.tokens;
+        if debt <= 0.0 {
+            Some(Duration::ZERO)
+        } else {
+            Duration::try_from_secs_f64(debt / self.limit.rate).ok()
+        }
+    }
+}
+
+// The buckets of one scope.
+#[derive(Debug, Clone)]
+pub struct Buckets {
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Buckets {
+    pub fn new(limits: &Limits) -> Self {
+        let now = Instant::now();
+        Buckets {
+            requests: limits.requests.map(|limit| TokenBucket::new(limit, now)),
+            bytes: limits.bytes.map(|limit| TokenBucket::new(limit, now)),
+        }
+    }
+
+    fn each(&mut self, requests: usize, bytes: usize) -> impl Iterator<Item = (&mut TokenBucket, f64)> {
+        let requests = self.requests.as_mut().map(|bucket| (bucket, requests as f64));
+        let bytes = self.bytes.as_mut().map(|bucket| (bucket, bytes as f64));
+        requests.into_iter().chain(bytes)
+    }
+
+    fn available(&mut self, requests: usize, bytes: usize, now: Instant) -> bool {
+        self.each(requests, bytes).all(|(bucket, amount)| bucket.available(amount, now))
+    }
+
+    // How long the request would have to wait for its tokens, or `None` if it would never get them.
+    fn wait(&mut self, requests: usize, bytes: usize, now: Instant) -> Option<Duration> {
+        self.each(requests, bytes).try_fold(Duration::ZERO, |wait, (bucket, amount)| Some(wait.max(bucket.wait(amount, now)?)))
+    }
+
+    fn take(&mut self, requests: usize, bytes: usize, now: Instant) {
+        for (bucket, amount) in self.each(requests, bytes) {
+            bucket.take(amount, now);
+        }
+    }
+}
+
+// Requests a message is charged as: one per entry of a batch, so batching does not get around the limits.
+pub fn requests(message: Option<&client_message::Message>) -> usize {
+    match message {
+        Some(client_message::Message::BatchRequest(batch)) => batch.messages.len().max(1),
+        _ => 1,
+    }
+}
+
+// Outcome of admitting one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Allowed,
+    Delayed(Duration),      // Allowed once the delay has passed.
+    Exceeded(&'static str), // Over the limit of the named scope; the request was not counted.
+}
+
+// Global and per-identity buckets shared by the connections of a server.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    global: Mutex<Buckets>,
+    identities: Mutex<HashMap<String, Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            global: Mutex::new(Buckets::new(&limits.global)),
+            identities: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Buckets for a new connection.
+    pub fn connection(&self) -> Buckets {
+        Buckets::new(&self.limits.per_connection)
+    }
+
+    // Counts `requests` requests of `bytes` in total against the connection, its identity and the global limits.
+    pub fn admit(&self, connection: &mut Buckets, identity: Option<&str>, requests: usize, bytes: usize) -> Admission {
+        let now = Instant::now();
+        let mut global = self.global.lock().unwrap();
+        let mut identities = self.identities.lock().unwrap();
+        let identity = identity.filter(|_| self.limits.per_identity.is_limited()).map(|identity| {
+            identities
+                .entry(identity.to_string())
+                .or_insert_with(|| Buckets::new(&self.limits.per_identity))
+        });
+
+        let mut scopes = vec![("connection", connection)];
+        scopes.extend(identity.map(|buckets| ("identity", buckets)));
+        scopes.push(("global", &mut *global));
+
+        let mut wait = Duration::ZERO;
+        for &mut (scope, ref mut buckets) in scopes.iter_mut() {
+            match buckets.wait(requests, bytes, now) {
+                None => return Admission::Exceeded(scope),
+                Some(_) if self.limits.on_excess != ExcessAction::Delay && !buckets.available(requests, bytes, now) => {
+                    return Admission::Exceeded(scope);
+                }
+                Some(scope_wait) => wait = wait.max(scope_wait),
+            }
+        }
+        for (_, buckets) in scopes {
+            buckets.take(requests, bytes, now);
+        }
+        if wait > Duration::ZERO && self.limits.on_excess == ExcessAction::Delay {
+            Admission::Delayed(wait)
+        } else {
+            Admission::Allowed
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+pub type ConnectionId = u64;
+
+// Snapshot of one live connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub peer: SocketAddr,
+    pub tls: bool,
+    pub connected_at: SystemTime,
+    pub last_activity: SystemTime, // Last request received or response sent.
+    pub bytes_in: u64,             // Request payloads, after decompression.
+    pub bytes_out: u64,            // Response payloads, before compression.
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub identity: Option<String>, // Set once the connection has authenticated.
+}
+
+// A live connection, updated by the thread serving it.
+#[derive(Debug)]
+pub struct Connection {
+    id: ConnectionId,
+    peer: SocketAddr,
+    tls: bool,
+    connected_at: SystemTime,
+    socket: TcpStream, // Clone of the connection's socket, used to force it closed.
+    last_activity: Mutex<SystemTime>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    identity: Mutex<Option<String>>,
+    closed: AtomicBool,
+}
+
+impl Connection {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        *self.last_activity.lock().unwrap() = SystemTime::now();
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        *self.last_activity.lock().unwrap() = SystemTime::now();
+    }
+
+    pub fn set_identity(&self, identity: &str) {
+        *self.identity.lock().unwrap() = Some(identity.to_string());
+    }
+
+    // Whether the connection was closed through `Registry::disconnect`.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    // Shuts the socket down, which wakes the serving thread out of any blocking read.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            peer: self.peer,
+            tls: self.tls,
+            connected_at: self.connected_at,
+            last_activity: *self.last_activity.lock().unwrap(),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            identity: self.identity.lock().unwrap().clone(),
+        }
+    }
+}
+
+// The live connections of a server.
+#[derive(Debug, Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<ConnectionId, Arc<Connection>>>,
+}
+
+// Keeps a connection listed until dropped.
+#[derive(Debug)]
+pub struct Registration {
+    registry: Arc<Registry>,
+    connection: Arc<Connection>,
+}
+
+impl Registration {
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap().remove(&self.connection.id);
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Lists a new connection. `socket` must be a clone of the connection's socket.
+    pub fn register(self: &Arc<Self>, peer: SocketAddr, socket: TcpStream, tls: bool) -> Registration {
+        let now = SystemTime::now();
+        let connection = Arc::new(Connection {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst) + 1,
+            peer,
+            tls,
+            connected_at: now,
+            socket,
+            last_activity: Mutex::new(now),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            messages_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            identity: Mutex::new(None),
+            closed: AtomicBool::new(false),
+        });
+        self.connections.lock().unwrap().insert(connection.id, connection.clone());
+        Registration {
+            registry: self.clone(),
+            connection,
+        }
+    }
+
+    // All live connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self.connections.lock().unwrap().values().map(|connection| connection.info()).collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    pub fn get(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        self.connections.lock().unwrap().get(&id).map(|connection| connection.info())
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Forcibly closes a connection. Returns false if no such connection is live.
+    pub fn disconnect(&self, id: ConnectionId) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(connection) => {
+                connection.close();
+                true
+            }
+            None => false,
+        }
+    }
+}
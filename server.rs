@@ -1,77 +1,614 @@
-use crate::message::{ClientMessage, client_message, ServerMessage, server_message, AddResponse};
-use log::{error, info, warn};
-use prost::Message;
+use crate::admin::AdminServer;
+use crate::arithmetic;
+use crate::audit::AuditEvent;
+use crate::auth;
+use crate::capture::{self, Direction};
+use crate::config::ServerConfig;
+use crate::connections::ConnectionLimiter;
+use crate::deadline::{DeadlineReader, Stalled};
+use crate::echo;
+use crate::encoding::Encoding;
+use crate::eval;
+use crate::frame::{self, FrameError};
+use crate::gateway::Gateway;
+use crate::handshake::{self, Features};
+use crate::http::HttpServer;
+use crate::logging::{PayloadLogging, Sampler};
+use crate::message::{
+    auth_request, client_message, server_message, AddResponse, AuthChallenge, AuthRequest, AuthResponse, BatchRequest,
+    BatchResponse, ClientMessage, CompressionAlgorithm, ErrorCode, ErrorResponse, Pong, ServerMessage,
+};
+use crate::metrics::{self, Metrics, ServerGauges};
+use crate::policy::MessageKind;
+use crate::ratelimit::{self, Admission, Buckets, ExcessAction, RateLimiter};
+use crate::registry::{Connection, ConnectionId, ConnectionInfo, Registry};
+use crate::tls::Stream;
+use crate::websocket::{self, WebSocket};
+use prost::Message as _;
 use std::{
-    io::{self, ErrorKind, Read, Write},
-    net::{TcpListener, TcpStream},
+    collections::HashSet,
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
     },
-    thread,
-    time::Duration,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
+use tracing::{debug, error, field, info, info_span, trace, warn, Span};
+
+fn error_response(code: ErrorCode, message: impl Into<String>) -> server_message::Message {
+    server_message::Message::Error(ErrorResponse {
+        code: code as i32,
+        message: message.into(),
+    })
+}
+
+// Computes the response for a single decoded request.
+fn handle_message(message: client_message::Message, config: &ServerConfig) -> server_message::Message {
+    match message {  // Match on the message type.
+        client_message::Message::EchoMessage(echo_message) => {
+            debug!("Received EchoMessage of {} bytes", echo_message.content.len());  // The content is logged per `LogConfig`.
+            server_message::Message::EchoMessage(echo_message)  // Respond with EchoMessage.
+        }
+        client_message::Message::EchoRequest(echo_request) => {
+            match echo::echo(&echo_request, config) {  // Apply the requested transformations.
+                Ok(response) => server_message::Message::EchoResponse(response),
+                Err(e) => server_message::Message::Error(e),  // Requested size or delay is over the limit.
+            }
+        }
+        client_message::Message::BinaryEchoRequest(binary_echo_request) => {
+            debug!("Received BinaryEchoRequest of {} bytes", binary_echo_request.payload.len());
+            match echo::binary_echo(binary_echo_request) {  // Echo the bytes back with the requested digest.
+                Ok(response) => server_message::Message::BinaryEchoResponse(response),
+                Err(e) => server_message::Message::Error(e),  // Unknown digest algorithm.
+            }
+        }
+        client_message::Message::Ping(ping) => {
+            server_message::Message::Pong(Pong { nonce: ping.nonce })  // Answer heartbeats with the same nonce.
+        }
+        client_message::Message::AddRequest(add_request) => match add_request.a.checked_add(add_request.b) {
+            Some(result) => server_message::Message::AddResponse(AddResponse { result }),  // Respond with AddResponse.
+            None => error_response(ErrorCode::Overflow, format!("{} + {} overflows", add_request.a, add_request.b)),
+        },
+        client_message::Message::EvalRequest(eval_request) => {
+            debug!("Received EvalRequest of {} bytes", eval_request.expression.len());  // The expression is logged per `LogConfig`.
+            server_message::Message::EvalResponse(eval::handle_request(&eval_request, &config.eval_limits))  // Respond with the value or a structured error.
+        }
+        client_message::Message::FloatArithmeticRequest(float_request) => {
+            match arithmetic::float(&float_request) {  // Perform floating-point arithmetic.
+                Ok(response) => server_message::Message::FloatArithmeticResponse(response),
+                Err(e) => server_message::Message::Error(e),  // Rejected operands or results.
+            }
+        }
+        client_message::Message::DecimalArithmeticRequest(decimal_request) => {
+            match arithmetic::decimal(&decimal_request) {  // Perform fixed-point decimal arithmetic.
+                Ok(response) => server_message::Message::DecimalArithmeticResponse(response),
+                Err(e) => server_message::Message::Error(e),  // Invalid scale, division by zero or overflow.
+            }
+        }
+        client_message::Message::BatchRequest(batch_request) => handle_batch(batch_request, config),
+        client_message::Message::Hello(_) => {
+            error_response(ErrorCode::InvalidArgument, "Hello is only valid as the first message of a connection")
+        }
+        client_message::Message::AuthRequest(_) => {
+            error_response(ErrorCode::InvalidArgument, "AuthRequest cannot be sent inside a batch")
+        }
+    }
+}
+
+// Answers one batch entry. Batches cannot be nested.
+fn handle_batch_entry(entry: ClientMessage, config: &ServerConfig) -> ServerMessage {
+    let response = match entry.message {
+        Some(client_message::Message::BatchRequest(_)) => {
+            error_response(ErrorCode::InvalidArgument, "nested batches are not supported")
+        }
+        Some(message) => handle_message(message, config),
+        None => error_response(ErrorCode::InvalidArgument, "batch entry contained no message"),
+    };
+    ServerMessage {
+        message: Some(response),
+        ..Default::default()
+    }
+}
+
+fn failed_entry() -> ServerMessage {
+    ServerMessage {
+        message: Some(error_response(ErrorCode::Unspecified, "the batch worker failed")),
+        ..Default::default()
+    }
+}
+
+fn handle_batch(batch: BatchRequest, config: &ServerConfig) -> server_message::Message {
+    debug!("Received BatchRequest with {} entries (parallel: {})", batch.messages.len(), batch.parallel);
+    if batch.messages.len() > config.max_batch_size {
+        return error_response(
+            ErrorCode::LimitExceeded,
+            format!("batch of {} entries exceeds the limit of {}", batch.messages.len(), config.max_batch_size),
+        );
+    }
+    // Each echo is bounded on its own; together they must still fit one frame and one echo delay.
+    let (size, delay) = batch
+        .messages
+        .iter()
+        .filter_map(|entry| match entry.message {
+            Some(client_message::Message::EchoRequest(ref echo)) => Some(echo),
+            _ => None,
+        })
+        .fold((0usize, Duration::ZERO), |(size, delay), echo| {
+            (size.saturating_add(echo::response_size(echo)), delay.saturating_add(Duration::from_millis(echo.delay_ms.into())))
+        });
+    if size > config.max_frame_size {
+        return error_response(
+            ErrorCode::LimitExceeded,
+            format!("echoes of {} bytes in total exceed the frame limit of {} bytes", size, config.max_frame_size),
+        );
+    }
+    if delay > config.max_echo_delay {
+        return error_response(
+            ErrorCode::LimitExceeded,
+            format!("echo delays of {:?} in total exceed the limit of {:?}", delay, config.max_echo_delay),
+        );
+    }
+
+    let responses = if batch.parallel && batch.messages.len() > 1 {
+        // Split the entries into one contiguous chunk per core; joining the chunks in order keeps responses aligned.
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = batch.messages.len().div_ceil(workers);
+        let mut entries = batch.messages.into_iter();
+        let chunks: Vec<Vec<ClientMessage>> = std::iter::from_fn(|| {
+            let chunk: Vec<ClientMessage> = entries.by_ref().take(chunk_size).collect();
+            (!chunk.is_empty()).then_some(chunk)
+        })
+        .collect();
+
+        let span = Span::current();  // Workers log under the connection and request of the batch.
+        thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| {
+                    let span = span.clone();
+                    let len = chunk.len();
+                    let handle = scope.spawn(move || {
+                        let _entered = span.enter();
+                        chunk.into_iter().map(|entry| handle_batch_entry(entry, config)).collect::<Vec<_>>()
+                    });
+                    (len, handle)
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|(len, handle)| handle.join().unwrap_or_else(|_| vec![failed_entry(); len]))
+                .collect()
+        })
+    } else {
+        batch.messages.into_iter().map(|entry| handle_batch_entry(entry, config)).collect()
+    };
+
+    let response = BatchResponse { responses };
+    if response.encoded_len() > config.max_frame_size {
+        return error_response(
+            ErrorCode::LimitExceeded,
+            format!("batch response of {} bytes exceeds the frame limit of {} bytes", response.encoded_len(), config.max_frame_size),
+        );
+    }
+    server_message::Message::BatchResponse(response)
+}
+
+// Applies the configured policy, recording denied requests in the audit trail.
+fn authorize(
+    config: &ServerConfig,
+    identity: Option<&str>,
+    peer: Option<SocketAddr>,
+    message: &client_message::Message,
+) -> Result<(), ErrorResponse> {
+    let Some(ref policy) = config.policy else {
+        return Ok(());
+    };
+    policy.check(identity, message).map_err(|reason| {
+        let event = AuditEvent {
+            time: SystemTime::now(),
+            peer,
+            identity: identity.map(str::to_string),
+            message: MessageKind::of(message).map_or("-", MessageKind::name),
+            reason,
+        };
+        warn!("Permission denied: {}", event);
+        if let Some(ref audit) = config.audit {
+            audit.record(&event);
+        }
+        ErrorResponse {
+            code: ErrorCode::PermissionDenied as i32,
+            message: event.reason,
+        }
+    })
+}
+
+// Tells a connection over the connection limits why it is being closed.
+fn refuse(stream: &mut Stream, config: &ServerConfig, encoding: Encoding, reason: String) {
+    let _ = stream.set_read_timeout(config.idle_timeout);  // Bounds a TLS handshake.
+    let response = ServerMessage {
+        message: Some(error_response(ErrorCode::TooManyConnections, reason)),
+        ..Default::default()
+    };
+    if let Err(e) = frame::Codec::new(config.max_frame_size).write(stream, &encoding.encode(&response)) {
+        warn!("Failed to send refusal: {}", e);
+    }
+}
+
+// Server-wide state shared by the listener, the connection threads and the admin listener.
+pub(crate) struct Shared {
+    pub(crate) config: RwLock<Arc<ServerConfig>>,  // Replaced on reload; connections keep the one they started with.
+    pub(crate) is_running: AtomicBool,
+    pub(crate) listening: AtomicBool,  // Set while `Server::run` accepts connections.
+    pub(crate) stopping: AtomicBool,  // Set by a stop or shutdown, until the server runs again.
+    pub(crate) released: AtomicBool,  // Set once the `Server` is dropped; ends the HTTP listener.
+    pub(crate) started: Instant,
+    pub(crate) accepted: AtomicU64,  // Connections accepted since the server was created, refused ones included.
+    pub(crate) corrupted_frames: AtomicU64,  // Frames that failed their checksum.
+    pub(crate) rate_limiter: RateLimiter,  // Identity and global limits.
+    pub(crate) connections: Arc<ConnectionLimiter>,
+    pub(crate) registry: Arc<Registry>,
+    pub(crate) disabled: RwLock<HashSet<MessageKind>>,  // Handlers switched off at runtime.
+    pub(crate) metrics: Metrics,
+    pub(crate) request_logs: Sampler,  // Budget of per-request log lines, sized at startup.
+}
+
+impl Shared {
+    pub(crate) fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub(crate) fn gauges(&self) -> ServerGauges {
+        ServerGauges {
+            accepted_connections: self.accepted.load(Ordering::SeqCst),
+            rejected_connections: self.connections.rejected_connections(),
+            open_connections: self.connections.open_connections() as u64,
+            max_connections: self.connections.max_connections(),
+            corrupted_frames: self.corrupted_frames.load(Ordering::SeqCst),
+        }
+    }
+
+    pub(crate) fn render_metrics(&self) -> String {
+        self.metrics.render(&self.gauges())
+    }
+
+    // Why the server is not alive, if it is not: its listener must be accepting connections.
+    pub(crate) fn liveness(&self) -> Result<(), String> {
+        match self.listening.load(Ordering::SeqCst) {
+            true => Ok(()),
+            false => Err("listener is not accepting connections".to_string()),
+        }
+    }
+
+    // Why the server should not be sent new connections, if it should not.
+    pub(crate) fn readiness(&self) -> Result<(), String> {
+        if self.stopping.load(Ordering::SeqCst) {
+            return Err("shutting down".to_string());
+        }
+        self.liveness()?;
+        match self.connections.max_connections() {
+            Some(max) if self.connections.open_connections() >= max => {
+                Err(format!("connection pool is full ({}/{})", self.connections.open_connections(), max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The first handler needed by `message`, batch entries included, that was switched off at runtime.
+    fn disabled_handler(&self, message: &client_message::Message) -> Option<MessageKind> {
+        let disabled = self.disabled.read().unwrap();
+        if disabled.is_empty() {
+            return None;
+        }
+        let mut kinds: Vec<MessageKind> = MessageKind::of(message).into_iter().collect();
+        if let client_message::Message::BatchRequest(batch) = message {
+            kinds.extend(batch.messages.iter().filter_map(|entry| entry.message.as_ref().and_then(MessageKind::of)));
+        }
+        kinds.into_iter().find(|kind| disabled.contains(kind))
+    }
+
+    // Answers a request from an established connection or the gateway: checks that its handlers are
+    // enabled and that the policy allows it, then runs it. A panic in a handler becomes an ErrorResponse.
+    pub(crate) fn dispatch(
+        &self,
+        config: &ServerConfig,
+        identity: Option<&str>,
+        peer: Option<SocketAddr>,
+        message: client_message::Message,
+    ) -> server_message::Message {
+        if let Some(kind) = self.disabled_handler(&message) {
+            return error_response(ErrorCode::HandlerDisabled, format!("{} is disabled", kind.name()));
+        }
+        match authorize(config, identity, peer, &message) {
+            // A handler that panics fails its own request rather than the connection, on every transport.
+            Ok(()) => panic::catch_unwind(AssertUnwindSafe(|| handle_message(message, config))).unwrap_or_else(|_| {
+                error!("Handler panicked");
+                error_response(ErrorCode::Unspecified, "the handler failed")
+            }),
+            Err(e) => server_message::Message::Error(e),
+        }
+    }
+
+    pub(crate) fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);  // Readiness fails from here on.
+        self.is_running.store(false, Ordering::SeqCst);
+    }
+
+    // Stops accepting connections, gives open ones up to `grace` to finish, then closes the rest.
+    pub(crate) fn shutdown(&self, grace: Duration) {
+        info!("Shutting down with a grace period of {:?}.", grace);
+        self.stop();
+        let deadline = Instant::now() + grace;
+        while !self.registry.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        for connection in self.registry.list() {
+            self.registry.disconnect(connection.id);
+        }
+    }
+}
 
 struct Client {
-    stream: TcpStream,
+    stream: Stream,
+    config: Arc<ServerConfig>,
+    shared: Arc<Shared>,
+    features: Option<Features>,  // Unset until the first message chooses between a handshake and legacy mode.
+    encoding: Option<Encoding>,  // Set by the listener, or else by the first frame.
+    buckets: Buckets,  // This connection's own limits.
+    identity: Option<String>,  // Set once the connection has authenticated.
+    challenge: Option<Vec<u8>>,  // Nonce of the last AuthChallenge, valid for one attempt.
+    connection: Arc<Connection>,  // This connection's entry in the server's registry.
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Self {
-        Client { stream }
+    pub fn new(
+        stream: Stream,
+        config: Arc<ServerConfig>,
+        shared: Arc<Shared>,
+        connection: Arc<Connection>,
+        encoding: Option<Encoding>,
+    ) -> Self {
+        Client {
+            stream,
+            config,
+            features: None,
+            encoding,
+            buckets: shared.rate_limiter.connection(),
+            shared,
+            identity: None,
+            challenge: None,
+            connection,
+        }
     }
 
-    pub fn handle(&mut self) -> io::Result<()> {
-        let mut buffer = [0; 512];
+    // Frame settings for the current state of the connection.
+    fn codec(&self) -> frame::Codec {
+        let compression = self.features.map_or(CompressionAlgorithm::None, |features| features.compression);
+        let checksums = self.features.is_some_and(|features| features.checksums);
+        frame::Codec::new(self.config.max_frame_size)
+            .with_compression(compression, self.config.compression_threshold)
+            .with_checksums(checksums)
+    }
+
+    fn send(&mut self, codec: frame::Codec, response: server_message::Message, request_id: u64) -> io::Result<()> {
+        let server_msg = ServerMessage {
+            message: Some(response),  // Set the response in the server message.
+            request_id,
+        };
+        let payload = self.encoding.unwrap_or_default().encode(&server_msg);
+        codec.write(&mut self.stream, &payload)?;  // Send the encoded response as one frame.
+        if let Some(ref capture) = self.config.capture {
+            capture.record(self.connection.id(), Direction::Outbound, &payload);
+        }
+        self.connection.sent(payload.len());
+        self.shared.metrics.sent(payload.len());
+        Ok(())
+    }
+
+    // The request ID to echo in a response, if request IDs were negotiated.
+    fn request_id(&self, request_id: u64) -> u64 {
+        match self.features {
+            Some(features) if features.request_ids => request_id,  // Correlate the response.
+            _ => 0,
+        }
+    }
 
-        loop {
-            match self.stream.read(&mut buffer) {  // Read from the client stream in a loop.
-                Ok(0) => {
+    // Computes the response to one request, applying the connection's handshake state.
+    // Returns the response and whether the connection must be closed after sending it.
+    fn respond(&mut self, message: client_message::Message) -> (server_message::Message, bool) {
+        match (self.features, message) {
+            (None, client_message::Message::Hello(hello)) => {
+                info!("Received Hello from '{}' (protocol version {})", hello.client_name, hello.protocol_version);
+                match handshake::negotiate(&hello, &self.config) {
+                    Ok((welcome, features)) => {
+                        self.features = Some(features);  // Remember what was negotiated for this connection.
+                        (server_message::Message::Welcome(welcome), false)
+                    }
+                    Err(e) => {
+                        warn!("Rejecting handshake: {}", e.message);
+                        (server_message::Message::Error(e), true)  // Incompatible client: reply and close.
+                    }
+                }
+            }
+            (None, _) if self.config.require_handshake => {
+                (error_response(ErrorCode::HandshakeRequired, "the first message must be a Hello"), true)
+            }
+            (None, message) => {
+                self.features = Some(Features::legacy());  // No handshake: fall back to the original protocol.
+                self.respond(message)
+            }
+            (Some(_), client_message::Message::Hello(_)) => {
+                (error_response(ErrorCode::InvalidArgument, "handshake already completed"), false)
+            }
+            (Some(_), client_message::Message::AuthRequest(request)) => self.authenticate(request),
+            (Some(_), _) if self.config.credentials.is_some() && self.identity.is_none() => {
+                (error_response(ErrorCode::Unauthenticated, "authentication required"), false)
+            }
+            (Some(features), client_message::Message::BatchRequest(_)) if !features.batching => {
+                (error_response(ErrorCode::NotNegotiated, "batching was not negotiated"), false)
+            }
+            (Some(_), message) => {
+                let peer = self.stream.peer_addr().ok();
+                (self.shared.dispatch(&self.config, self.identity.as_deref(), peer, message), false)
+            }
+        }
+    }
+
+    // Issues a challenge or checks credentials. Failed attempts close the connection.
+    fn authenticate(&mut self, request: AuthRequest) -> (server_message::Message, bool) {
+        let Some(ref credentials) = self.config.credentials else {
+            return (error_response(ErrorCode::InvalidArgument, "authentication is not enabled"), false);
+        };
+        if self.identity.is_some() {
+            return (error_response(ErrorCode::InvalidArgument, "already authenticated"), false);
+        }
+        if let Some(auth_request::Credential::Challenge(_)) = request.credential {
+            let nonce = auth::challenge();
+            self.challenge = Some(nonce.clone());
+            return (server_message::Message::AuthChallenge(AuthChallenge { nonce }), false);
+        }
+
+        let challenge = self.challenge.take();  // A challenge answers at most one attempt.
+        match auth::verify(credentials.as_ref(), &request, challenge.as_deref()) {
+            Ok(()) => {
+                info!("Authenticated '{}'", request.identity);
+                self.identity = Some(request.identity.clone());
+                self.connection.set_identity(&request.identity);
+                (server_message::Message::AuthResponse(AuthResponse { identity: request.identity }), false)
+            }
+            Err(e) => {
+                warn!("Rejecting credentials for '{}': {}", request.identity, e.message);
+                (server_message::Message::Error(e), true)
+            }
+        }
+    }
+
+    // Logs a decoded request within the configured budget, with its payload in the configured form.
+    fn log_request(&self, request: &ClientMessage) {
+        let (admitted, suppressed) = self.shared.request_logs.admit();
+        if suppressed > 0 {
+            info!("Suppressed {} request log lines in the last second.", suppressed);
+        }
+        if !admitted {
+            return;
+        }
+        match self.config.logging.payloads {
+            PayloadLogging::Full => trace!("Decoded client message: {}", self.config.logging.describe(request)),
+            _ => info!("Decoded client message: {}", self.config.logging.describe(request)),
+        }
+    }
+
+    pub fn handle(&mut self) -> io::Result<()> {
+        while self.shared.is_running.load(Ordering::SeqCst) {  // Serve requests while the server is running.
+            let codec = self.codec();  // Captured before the request so a Welcome is still sent uncompressed.
+            // Silent clients time out instead of blocking forever, and slow senders cannot hold a frame open.
+            let mut reader = DeadlineReader::new(
+                &mut self.stream,
+                self.config.idle_timeout,
+                self.config.frame_timeout,
+                self.config.min_transfer_rate,
+            );
+            match codec.read(&mut reader) {  // Read one frame from the client stream.
+                Ok(None) | Err(_) if self.connection.is_closed() => {
+                    info!("Connection {} closed by the server.", self.connection.id());
+                    return Ok(());
+                }
+                Ok(None) => {
                     info!("Client disconnected.");
                     return Ok(()); // If no data is read, client is disconnected.
                 }
-                Ok(bytes_read) => {
-                    let received_data = &buffer[..bytes_read];  // Slice the buffer to received data.
-
-                    match ClientMessage::decode(received_data) {  // Decode the incoming message.
+                Ok(Some(received_data)) => {
+                    self.shared.metrics.received(received_data.len());
+                    let encoding = *self.encoding.get_or_insert_with(|| {
+                        let encoding = if self.config.accept_json { Encoding::detect(&received_data) } else { Encoding::Protobuf };
+                        debug!("Connection {} speaks {:?}.", self.connection.id(), encoding);
+                        encoding
+                    });
+                    if let Some(ref capture) = self.config.capture {
+                        let payload = capture::redact(encoding, &received_data);  // Tokens and signatures never reach the file.
+                        capture.record(self.connection.id(), Direction::Inbound, &payload);
+                    }
+                    match encoding.decode::<ClientMessage>(&received_data) {  // Decode the incoming message.
                         Ok(client_msg) => {
-                            info!("Decoded client message: {:?}", client_msg);  // Log the decoded message.
+                            let span = info_span!("request", request_id = client_msg.request_id, variant = field::Empty);
+                            if let Some(ref message) = client_msg.message {
+                                span.record("variant", metrics::variant(message));
+                            }
+                            let _entered = span.enter();
+                            self.log_request(&client_msg);
+                            self.connection.received(received_data.len());
 
-                            if let Some(message) = client_msg.message {  // Check if there is a message.
-                                let response = match message {  // Match on the message type.
-                                    client_message::Message::EchoMessage(echo_message) => {
-                                        info!("Received EchoMessage: {}", echo_message.content);  // Log EchoMessage content.
-                                        server_message::Message::EchoMessage(echo_message)  // Respond with EchoMessage.
+                            let requests = ratelimit::requests(client_msg.message.as_ref());
+                            match self.shared.rate_limiter.admit(&mut self.buckets, self.identity.as_deref(), requests, received_data.len()) {
+                                Admission::Allowed => {}
+                                Admission::Delayed(wait) => thread::sleep(wait),  // Throttle instead of refusing.
+                                Admission::Exceeded(scope) => {
+                                    warn!("Rate limit of the {} exceeded", scope);
+                                    let response = error_response(ErrorCode::RateLimited, format!("{} rate limit exceeded", scope));
+                                    let disconnect = self.config.rate_limits.on_excess == ExcessAction::Disconnect;
+                                    if let Err(e) = self.send(codec, response, self.request_id(client_msg.request_id)) {
+                                        error!("Failed to send response: {}", e);
+                                        break;
                                     }
-                                    client_message::Message::AddRequest(add_request) => {
-                                        let result = add_request.a + add_request.b;  // Perform addition for AddRequest.
-                                        server_message::Message::AddResponse(AddResponse { result })  // Respond with AddResponse.
+                                    if disconnect {
+                                        return Ok(());
                                     }
-                                };
-
-                                let mut response_buffer = Vec::new();  // Prepare a buffer to encode the response.
-                                let server_msg = ServerMessage {
-                                    message: Some(response),  // Set the response in the server message.
-                                };
-                                if let Err(e) = server_msg.encode(&mut response_buffer) {  // Encode the response.
-                                    error!("Failed to encode response: {}", e);  // Log encoding failure.
-                                    continue;  // Skip the current iteration if encoding fails.
+                                    continue;
                                 }
-                                if let Err(e) = self.stream.write_all(&response_buffer) {  // Send the encoded response.
+                            }
+
+                            if let Some(message) = client_msg.message {  // Check if there is a message.
+                                let (variant, started) = (metrics::variant(&message), Instant::now());
+                                let (response, close) = self.respond(message);
+                                self.shared.metrics.request(variant, started.elapsed());
+                                let request_id = self.request_id(client_msg.request_id);  // After `respond`, which may negotiate it.
+                                if let Err(e) = self.send(codec, response, request_id) {  // Send the encoded response.
                                     error!("Failed to send response: {}", e);  // Log if sending fails.
                                     break;
                                 }
+                                if close {
+                                    return Ok(());  // Handshake or authentication failed: the response explained why.
+                                }
                             } else {
                                 warn!("ClientMessage contained no message");  // Warn if no message is present.
                             }
                         }
                         Err(e) => {
+                            self.shared.metrics.decode_error();
                             warn!("Failed to decode message: {}", e);  // Warn if message decoding fails.
                         }
                     }
                 }
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    break;  // Exit the loop if there is no data to read.
+                Err(e) if Stalled::from_io(&e).is_some() => {
+                    warn!("Closing slow client: {}", e);
+                    return Ok(());
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    info!("Closing idle connection after {:?} without data.", self.config.idle_timeout);
+                    return Ok(());  // Read timed out: the client went silent.
+                }
+                Err(e) if FrameError::from_io(&e).is_some_and(FrameError::is_corruption) => {
+                    // The frame was consumed whole, so drop it, tell the client and keep reading.
+                    self.shared.corrupted_frames.fetch_add(1, Ordering::SeqCst);
+                    warn!("Dropping corrupted frame: {}", e);
+                    if let Err(e) = self.send(codec, error_response(ErrorCode::CorruptedFrame, e.to_string()), 0) {
+                        error!("Failed to send response: {}", e);
+                        break;
+                    }
+                }
+                Err(e) if FrameError::from_io(&e).is_some() => {
+                    // The stream cannot be resynchronised after a rejected frame: report and close.
+                    warn!("Rejecting client frame: {}", e);
+                    self.shared.metrics.frame_error();
+                    let code = match FrameError::from_io(&e) {
+                        Some(FrameError::TooLarge { .. }) => ErrorCode::LimitExceeded,
+                        _ => ErrorCode::InvalidArgument,
+                    };
+                    let _ = self.send(codec, error_response(code, e.to_string()), 0);
+                    return Err(e);
                 }
                 Err(e) => {
                     error!("Error reading from client: {}", e);  // Log any read error from the client.
@@ -84,58 +621,234 @@ impl Client {
     }
 }
 
+// How the connections of a listener carry their frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Framed,     // Length-prefixed frames straight over TCP or TLS.
+    WebSocket,  // One binary WebSocket message per frame; see `websocket::WebSocket`.
+}
+
 pub struct Server {
     listener: TcpListener,
-    is_running: Arc<AtomicBool>,
+    json_listener: Option<TcpListener>,  // Connections accepted here speak JSON from the first frame.
+    websocket_listener: Option<TcpListener>,
+    shared: Arc<Shared>,
+    admin: Option<Arc<AdminServer>>,  // Bound at creation so a busy admin address fails early.
+    gateway: Option<Arc<Gateway>>,  // Likewise; serves while the server runs.
+    http: Option<JoinHandle<()>>,  // Serves from creation until the server is dropped, so health checks see every state.
 }
 
 impl Server {
     pub fn new(addr: &str) -> io::Result<Self> {
+        Self::with_config(addr, ServerConfig::default())
+    }
+
+    pub fn with_config(addr: &str, config: ServerConfig) -> io::Result<Self> {
+        config.rate_limits.validate().map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("invalid rate limit: {}", e)))?;
         let listener = TcpListener::bind(addr)?;  // Bind the server to the provided address.
-        let is_running = Arc::new(AtomicBool::new(false));  // Atomic boolean to track if the server is running.
+        let json_listener = config.json_addr.as_deref().map(TcpListener::bind).transpose()?;
+        let websocket_listener = config.websocket_addr.as_deref().map(TcpListener::bind).transpose()?;
+        let admin = config.admin.clone().map(AdminServer::bind).transpose()?.map(Arc::new);
+        let gateway = config.gateway_addr.as_deref().map(Gateway::bind).transpose()?.map(Arc::new);
+        let http = config.http_addr.as_deref().map(HttpServer::bind).transpose()?;
+        let shared = Shared {
+            is_running: AtomicBool::new(false),  // Atomic boolean to track if the server is running.
+            listening: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            released: AtomicBool::new(false),
+            started: Instant::now(),
+            accepted: AtomicU64::new(0),
+            corrupted_frames: AtomicU64::new(0),
+            rate_limiter: RateLimiter::new(config.rate_limits),
+            connections: Arc::new(ConnectionLimiter::new(config.max_connections, config.max_connections_per_ip)),
+            registry: Arc::new(Registry::new()),
+            disabled: RwLock::new(HashSet::new()),
+            metrics: Metrics::new(),
+            request_logs: Sampler::new(config.logging.max_request_logs_per_sec),
+            config: RwLock::new(Arc::new(config)),
+        };
+        let shared = Arc::new(shared);
+        let http = http.map(|http| {
+            let shared = shared.clone();
+            thread::spawn(move || http.run(shared))
+        });
         Ok(Server {
             listener,
-            is_running,
+            json_listener,
+            websocket_listener,
+            shared,
+            admin,
+            gateway,
+            http,
         })
     }
 
     pub fn run(&self) -> io::Result<()> {
-        self.is_running.store(true, Ordering::SeqCst);  // Mark server as running.
+        self.shared.is_running.store(true, Ordering::SeqCst);  // Mark server as running.
+        self.shared.stopping.store(false, Ordering::SeqCst);
         info!("Server running on {}", self.listener.local_addr()?);  // Log the server address.
         self.listener.set_nonblocking(true)?;  // Set listener to non-blocking mode.
+        if let Some(ref listener) = self.json_listener {
+            info!("Serving JSON on {}", listener.local_addr()?);
+            listener.set_nonblocking(true)?;
+        }
+        if let Some(ref listener) = self.websocket_listener {
+            info!("Serving WebSocket on {}", listener.local_addr()?);
+            listener.set_nonblocking(true)?;
+        }
+        let admin = self.admin.clone().map(|admin| {
+            let shared = self.shared.clone();
+            thread::spawn(move || admin.run(shared))  // Stops along with the server.
+        });
+        let gateway = self.gateway.clone().map(|gateway| {
+            let shared = self.shared.clone();
+            thread::spawn(move || gateway.run(shared))
+        });
 
-        while self.is_running.load(Ordering::SeqCst) {
-            match self.listener.accept() {
-                Ok((stream, addr)) => {
-                    info!("New client connected: {}", addr);
-
-                    let is_running = self.is_running.clone();  // Clone the running flag for the thread.
-                    thread::spawn(move || {  // Spawn a new thread to handle the client.
-                        let mut client = Client::new(stream);
-                        while is_running.load(Ordering::SeqCst) {  // While the server is running.
-                            if let Err(e) = client.handle() {  // Handle client communication.
-                                error!("Error handling client: {}", e);
-                                break;
-                            }
-                        }
-                        info!("Client {} disconnected.", addr);  // Log client disconnection.
-                    });
-                }
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(10));  // Sleep briefly if there are no connections.
-                }
-                Err(e) => {
-                    error!("Error accepting connection: {}", e);  // Log error if accepting connection fails.
+        self.shared.listening.store(true, Ordering::SeqCst);
+        while self.shared.is_running.load(Ordering::SeqCst) {
+            let mut accepted = false;
+            for (listener, encoding, transport) in self.listeners() {
+                match listener.accept() {
+                    Ok((stream, addr)) => {
+                        accepted = true;
+                        self.spawn(stream, addr, encoding, transport);
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        error!("Error accepting connection: {}", e);  // Log error if accepting connection fails.
+                    }
                 }
             }
+            if !accepted {
+                thread::sleep(Duration::from_millis(10));  // Sleep briefly if there are no connections.
+            }
         }
 
+        self.shared.listening.store(false, Ordering::SeqCst);
+        for listener in admin.into_iter().chain(gateway) {
+            let _ = listener.join();
+        }
         info!("Server stopped.");  // Log when server stops.
         Ok(())
     }
 
+    // Every data listener, with the encoding its connections are held to (`None` lets the first frame
+    // decide) and how they carry frames.
+    fn listeners(&self) -> impl Iterator<Item = (&TcpListener, Option<Encoding>, Transport)> {
+        let json = self.json_listener.iter().map(|listener| (listener, Some(Encoding::Json), Transport::Framed));
+        let websocket = self.websocket_listener.iter().map(|listener| (listener, Some(Encoding::Protobuf), Transport::WebSocket));
+        std::iter::once((&self.listener, None, Transport::Framed)).chain(json).chain(websocket)
+    }
+
+    // Serves an accepted connection on its own thread.
+    fn spawn(&self, stream: TcpStream, addr: SocketAddr, encoding: Option<Encoding>, transport: Transport) {
+        info!("New client connected: {}", addr);
+
+        self.shared.accepted.fetch_add(1, Ordering::SeqCst);
+        let shared = self.shared.clone();  // Share the server state with the thread.
+        let mut config = self.shared.config();  // The connection keeps this configuration even if it is reloaded.
+        if transport == Transport::WebSocket {
+            config = Arc::new(websocket::connection_config(&config));
+        }
+        let permit = self.shared.connections.acquire(addr.ip());  // Counted before the thread starts.
+        thread::spawn(move || {  // Spawn a new thread to handle the client.
+            let span = info_span!("connection", peer = %addr, id = field::Empty);
+            let _entered = span.enter();
+            let socket = stream.try_clone();  // Lets the registry close the connection.
+            let stream = match config.tls {
+                Some(ref tls) => Stream::accept(stream, tls.clone()),
+                None => Ok(Stream::Plain(stream)),
+            };
+            let stream = match transport {
+                Transport::Framed => stream,
+                Transport::WebSocket => stream.and_then(|stream| {
+                    let _ = stream.set_read_timeout(config.idle_timeout);  // Bounds the opening handshake.
+                    let socket = WebSocket::accept(stream, config.max_frame_size)?;
+                    Ok(Stream::WebSocket(Box::new(socket)))
+                }),
+            };
+            let (mut stream, socket) = match (stream, socket) {
+                (Ok(stream), Ok(socket)) => (stream, socket),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("Failed to set up connection {}: {}", addr, e);
+                    return;
+                }
+            };
+            match permit {
+                Ok(_permit) => {  // Frees the slot once the client is done.
+                    let registration = shared.registry.register(addr, socket, stream.is_tls());
+                    let connection = registration.connection().clone();
+                    span.record("id", connection.id());
+                    let mut client = Client::new(stream, config, shared, connection, encoding);
+                    let result = client.handle();  // Handle client communication until it disconnects.
+                    if let Err(ref e) = result {
+                        error!("Error handling client: {}", e);
+                    }
+                    if let Stream::WebSocket(ref mut socket) = client.stream {
+                        let stopping = !client.shared.is_running.load(Ordering::SeqCst);
+                        socket.close(websocket::close_code(&result, stopping), "");
+                    }
+                    let _ = client.stream.shutdown();  // Lets a TLS client tell a clean close from truncation.
+                }
+                Err(reason) => {
+                    warn!("Refusing connection from {}: {}", addr, reason);
+                    refuse(&mut stream, &config, encoding.unwrap_or_default(), reason.clone());
+                    if let Stream::WebSocket(ref mut socket) = stream {
+                        socket.close(websocket::CLOSE_TRY_AGAIN_LATER, &reason);
+                    }
+                    let _ = stream.shutdown();
+                }
+            }
+            info!("Client {} disconnected.", addr);  // Log client disconnection.
+        });
+    }
+
+    // Number of frames that failed their checksum since the server was created.
+    pub fn corrupted_frames(&self) -> u64 {
+        self.shared.corrupted_frames.load(Ordering::SeqCst)
+    }
+
+    // Connections currently being served.
+    pub fn open_connections(&self) -> usize {
+        self.shared.connections.open_connections()
+    }
+
+    // Connections refused for exceeding `max_connections` or `max_connections_per_ip`.
+    pub fn rejected_connections(&self) -> u64 {
+        self.shared.connections.rejected_connections()
+    }
+
+    // Live connections, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.shared.registry.list()
+    }
+
+    pub fn connection(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        self.shared.registry.get(id)
+    }
+
+    // Forcibly closes a connection. Returns false if no such connection is live.
+    pub fn disconnect(&self, id: ConnectionId) -> bool {
+        self.shared.registry.disconnect(id)
+    }
+
+    // Current metrics in the Prometheus text format, as served on `/metrics`.
+    pub fn metrics(&self) -> String {
+        self.shared.render_metrics()
+    }
+
     pub fn stop(&self) {
-        self.is_running.store(false, Ordering::SeqCst);  // Set the server running flag to false.
+        self.shared.stop();  // Set the server running flag to false.
         info!("Server stopping.");  // Log when the server is stopped.
     }
 }
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.shared.released.store(true, Ordering::SeqCst);
+        if let Some(http) = self.http.take() {
+            let _ = http.join();
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::websocket::WebSocket;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConnection, RootCertStore, ServerConnection, StreamOwned};
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Reads every certificate from a PEM file.
+pub fn load_certs(path: impl AsRef<Path>) -> io::Result<Vec<CertificateDer<'static>>> {
+    let path = path.as_ref();
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("{}: no certificates found", path.display())));
+    }
+    Ok(certs)
+}
+
+// Reads the first private key (PKCS#8, PKCS#1 or SEC1) from a PEM file.
+pub fn load_private_key(path: impl AsRef<Path>) -> io::Result<PrivateKeyDer<'static>> {
+    let path = path.as_ref();
+    PrivateKeyDer::from_pem_file(path).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+}
+
+fn root_store(ca_path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(cert).map_err(|e| invalid(format!("{}: {}", ca_path.display(), e)))?;
+    }
+    Ok(roots)
+}
+
+// Builds a server configuration from a PEM certificate chain and key. With `client_ca` set,
+// clients must present a certificate signed by one of its CAs (mutual TLS).
+pub fn server_config(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<rustls::ServerConfig>> {
+    let builder = match client_ca {
+        Some(ca_path) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(root_store(ca_path)?))
+                .build()
+                .map_err(|e| invalid(format!("{}: {}", ca_path.display(), e)))?;
+            rustls::ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => rustls::ServerConfig::builder().with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+// Builds a client configuration trusting only the CAs in `ca_path`, optionally presenting a
+// certificate chain and key for mutual TLS.
+pub fn client_config(
+    ca_path: impl AsRef<Path>,
+    identity: Option<(&Path, &Path)>,
+) -> io::Result<Arc<rustls::ClientConfig>> {
+    let builder = rustls::ClientConfig::builder().with_root_certificates(root_store(ca_path.as_ref())?);
+    let config = match identity {
+        Some((cert_path, key_path)) => builder
+            .with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+            .map_err(|e| invalid(e.to_string()))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+// A connection that is either plain TCP or TLS over TCP, possibly carrying WebSocket messages.
+pub enum Stream {
+    Plain(TcpStream),
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+    WebSocket(Box<WebSocket>),
+}
+
+impl Stream {
+    // Wraps an accepted connection; the TLS handshake runs on the first read.
+    pub fn accept(stream: TcpStream, config: Arc<rustls::ServerConfig>) -> io::Result<Self> {
+        let connection = ServerConnection::new(config).map_err(|e| invalid(e.to_string()))?;
+        Ok(Stream::Server(Box::new(StreamOwned::new(connection, stream))))
+    }
+
+    // Wraps an outgoing connection and completes the TLS handshake, verifying the server as `server_name`.
+    pub fn connect(mut stream: TcpStream, config: Arc<rustls::ClientConfig>, server_name: &str) -> io::Result<Self> {
+        let name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", server_name, e)))?;
+        let mut connection = ClientConnection::new(config, name).map_err(|e| invalid(e.to_string()))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(Stream::Client(Box::new(StreamOwned::new(connection, stream))))
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Server(stream) => stream.get_ref(),
+            Stream::Client(stream) => stream.get_ref(),
+            Stream::WebSocket(socket) => socket.get_ref().tcp(),
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            Stream::WebSocket(socket) => socket.get_ref().is_tls(),
+            _ => true,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    // Start and progress of a frame received but not yet readable; see `WebSocket::partial_frame`.
+    pub fn partial_frame(&self) -> Option<(Instant, u64)> {
+        match self {
+            Stream::WebSocket(socket) => socket.partial_frame(),
+            _ => None,
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    // Closes the connection, telling a TLS peer first so it sees a clean end of stream.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(_) => {}
+            Stream::Server(stream) => {
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+            }
+            Stream::Client(stream) => {
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+            }
+            Stream::WebSocket(socket) => return socket.shutdown(),  // Sends a close frame first.
+        }
+        self.tcp().shutdown(Shutdown::Both)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Server(stream) => stream.read(buf),
+            Stream::Client(stream) => stream.read(buf),
+            Stream::WebSocket(socket) => socket.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Server(stream) => stream.write(buf),
+            Stream::Client(stream) => stream.write(buf),
+            Stream::WebSocket(socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Server(stream) => stream.flush(),
+            Stream::Client(stream) => stream.flush(),
+            Stream::WebSocket(socket) => socket.flush(),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use crate::config::ServerConfig;
+use crate::frame::{FrameError, FLAG_CHECKSUM, FLAG_COMPRESSED, HEADER_LEN, LENGTH_MASK};
+use crate::http::{self, Response};
+use crate::message::Capability;
+use crate::tls::Stream;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+use std::{
+    cmp,
+    io::{self, ErrorKind, Read, Write},
+    time::Instant,
+};
+use tracing::debug;
+
+// Close codes from RFC 6455, section 7.4.1.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001; // The server is stopping.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003; // A text message: only binary messages carry requests.
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+pub const CLOSE_TRY_AGAIN_LATER: u16 = 1013; // Over the connection limits.
+
+// Appended to the client's key to compute the accept key of the handshake.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Largest payload of a control frame.
+const MAX_CONTROL_LEN: usize = 125;
+
+// The `Sec-WebSocket-Accept` answering a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    STANDARD.encode(Sha1::digest(format!("{}{}", key, ACCEPT_GUID)))
+}
+
+// Why the upgrade request of a connection was refused, if it was.
+fn check_upgrade(request: &http::Request) -> Result<&str, Response> {
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+    };
+    if request.method != "GET" {
+        return Err(Response::text(405, "WebSocket upgrades must use GET\n").with_header("Allow", "GET"));
+    }
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(Response::text(426, "this listener only speaks WebSocket\n").with_header("Upgrade", "websocket"));
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(Response::text(426, "only WebSocket version 13 is supported\n").with_header("Sec-WebSocket-Version", "13"));
+    }
+    match request.header("Sec-WebSocket-Key") {
+        Some(key) if STANDARD.decode(key).is_ok_and(|key| key.len() == 16) => Ok(key),
+        _ => Err(Response::text(400, "missing or invalid Sec-WebSocket-Key\n")),
+    }
+}
+
+// The configuration a WebSocket connection is served with. Messages are already delimited and, over
+// TLS, protected by the transport, so frames on them are never compressed or checksummed.
+pub(crate) fn connection_config(config: &ServerConfig) -> ServerConfig {
+    let mut config = config.clone();
+    config.compression.clear();
+    config.capabilities.retain(|capability| !matches!(capability, Capability::Compression | Capability::Checksums));
+    config
+}
+
+// The close code for a connection whose handler returned `result`.
+pub(crate) fn close_code(result: &io::Result<()>, stopping: bool) -> u16 {
+    match result {
+        Ok(()) if stopping => CLOSE_GOING_AWAY,
+        Ok(()) => CLOSE_NORMAL,
+        Err(e) if matches!(FrameError::from_io(e), Some(FrameError::TooLarge { .. })) => CLOSE_MESSAGE_TOO_BIG,
+        Err(_) => CLOSE_INTERNAL_ERROR,
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("WebSocket protocol error: {}", message))
+}
+
+// The server side of a WebSocket connection (RFC 6455), presented to the rest of the server as a
+// stream of frames: every binary message read is handed out as one frame, with the header a framed
+// connection would have sent, and every frame written is sent as one binary message. Pings are
+// answered and a close is echoed here, so the server only ever sees requests. The frame deadlines
+// start with the first byte of a WebSocket frame, see `partial_frame`, not when the message is handed out:
+// reads of a message in progress fail with `WouldBlock` between socket reads, so the caller can check them.
+pub struct WebSocket {
+    stream: Stream,
+    max_message: usize,
+    input: Vec<u8>,                 // Bytes read but not yet parsed into WebSocket frames.
+    message: Option<(u8, Vec<u8>)>, // Opcode and payload of a fragmented message in progress.
+    frame: Vec<u8>,                 // The frame being handed out, and how much of it has been read.
+    read: usize,
+    output: Vec<u8>, // Frame bytes written but not yet sent.
+    closed: bool,    // A close frame was sent; nothing more may follow it.
+    receiving: Option<(Instant, u64)>, // When the first byte of the message being received arrived, and the bytes since.
+}
+
+impl WebSocket {
+    // Completes the opening handshake of an accepted connection. Requests that are not upgrades
+    // are answered with an HTTP error and fail. Messages over `max_message` bytes are rejected.
+    pub fn accept(mut stream: Stream, max_message: usize) -> io::Result<Self> {
+        let key = http::read_request(&mut stream, 0).and_then(|request| check_upgrade(&request).map(accept_key));
+        let key = match key {
+            Ok(key) => key,
+            Err(response) => {
+                let _ = response.write_to(&mut stream);
+                let reason = response.body.trim_end().to_string();
+                return Err(io::Error::new(ErrorKind::InvalidData, format!("WebSocket handshake failed: {}", reason)));
+            }
+        };
+        let head = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            key
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.flush()?;
+        Ok(WebSocket {
+            stream,
+            max_message,
+            input: Vec::new(),
+            message: None,
+            frame: Vec::new(),
+            read: 0,
+            output: Vec::new(),
+            closed: false,
+            receiving: None,
+        })
+    }
+
+    pub fn get_ref(&self) -> &Stream {
+        &self.stream
+    }
+
+    // When the first byte of a message still being received arrived, and how many bytes have arrived
+    // since. `None` while no message is in progress, including while one is being handed out.
+    pub fn partial_frame(&self) -> Option<(Instant, u64)> {
+        self.receiving
+    }
+
+    // Restarts the clock after a WebSocket frame was consumed: a fragmented message keeps its start,
+    // bytes already buffered begin the next message now and otherwise nothing is in progress.
+    fn consumed(&mut self) {
+        if self.message.is_none() {
+            self.receiving = (!self.input.is_empty()).then(|| (Instant::now(), self.input.len() as u64));
+        }
+    }
+
+    // Sends a normal close, unless a close was already sent, and closes the underlying stream.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.close(CLOSE_NORMAL, "");
+        self.stream.shutdown()
+    }
+
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "the WebSocket connection is closed"));
+        }
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);  // A final frame: messages are never fragmented.
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    // Sends a close frame with `code`, unless one was already sent. The reason is cut to fit the frame.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.closed {
+            return;
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        let end = (0..=cmp::min(reason.len(), MAX_CONTROL_LEN - 2)).rev().find(|end| reason.is_char_boundary(*end)).unwrap_or(0);
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        if let Err(e) = self.send(OPCODE_CLOSE, &payload) {
+            debug!("Failed to send WebSocket close: {}", e);
+        }
+        self.closed = true;
+    }
+
+    // Closes the connection with `code` and returns the error to report.
+    fn fail(&mut self, code: u16, error: io::Error) -> io::Error {
+        self.close(code, &error.to_string());
+        error
+    }
+
+    // Parses one WebSocket frame from `input`, if it holds a whole one: its FIN bit, opcode and unmasked payload.
+    fn parse(&mut self) -> io::Result<Option<(bool, u8, Vec<u8>)>> {
+        let Some(&[first, second]) = self.input.get(..2) else {
+            return Ok(None);
+        };
+        let (fin, opcode) = (first & 0x80 != 0, first & 0x0F);
+        if first & 0x70 != 0 {
+            return Err(protocol_error("reserved bits set without a negotiated extension"));
+        }
+        if second & 0x80 == 0 {
+            return Err(protocol_error("client frames must be masked"));
+        }
+        let (len, mut offset) = match second & 0x7F {
+            126 => match self.input.get(2..4) {
+                Some(bytes) => (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4),
+                None => return Ok(None),
+            },
+            127 => match self.input.get(2..10) {
+                Some(bytes) => (u64::from_be_bytes(bytes.try_into().unwrap()), 10),
+                None => return Ok(None),
+            },
+            len => (len as u64, 2),
+        };
+        if opcode >= OPCODE_CLOSE && (!fin || len > MAX_CONTROL_LEN as u64) {
+            return Err(protocol_error("control frames must be final and at most 125 bytes"));
+        }
+        let buffered = self.message.as_ref().map_or(0, |(_, payload)| payload.len()) as u64;
+        if opcode < OPCODE_CLOSE && buffered + len > self.max_message as u64 {
+            let len = usize::try_from(buffered + len).unwrap_or(usize::MAX);
+            return Err(FrameError::TooLarge { len, max: self.max_message }.into());
+        }
+        let len = len as usize;
+        let Some(mask) = self.input.get(offset..offset + 4).map(|mask| [mask[0], mask[1], mask[2], mask[3]]) else {
+            return Ok(None);
+        };
+        offset += 4;
+        if self.input.len() < offset + len {
+            return Ok(None);
+        }
+        let mut payload: Vec<u8> = self.input.drain(..offset + len).skip(offset).collect();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Some((fin, opcode, payload)))
+    }
+
+    // Reads until a whole binary message has arrived and returns it, or `None` once the connection is closed.
+    fn next_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut waited = false;
+        loop {
+            if self.closed {
+                return Ok(None);
+            }
+            let parsed = match self.parse() {
+                Ok(parsed) => parsed,
+                Err(e) if FrameError::from_io(&e).is_some() => return Err(e),  // Closed once the server has answered it.
+                Err(e) => return Err(self.fail(CLOSE_PROTOCOL_ERROR, e)),
+            };
+            let Some((fin, opcode, payload)) = parsed else {
+                if waited && self.receiving.is_some() {
+                    return Err(io::Error::new(ErrorKind::WouldBlock, "WebSocket message incomplete"));
+                }
+                waited = true;
+                let mut buffer = [0u8; 4096];
+                match self.stream.read(&mut buffer)? {
+                    0 => return Ok(None),
+                    read => {
+                        self.input.extend_from_slice(&buffer[..read]);
+                        self.receiving.get_or_insert_with(|| (Instant::now(), 0)).1 += read as u64;
+                    }
+                }
+                continue;
+            };
+            self.consumed();
+            let message = match (opcode, self.message.take()) {
+                (OPCODE_PING, message) => {
+                    self.message = message;
+                    self.send(OPCODE_PONG, &payload)?;
+                    continue;
+                }
+                (OPCODE_PONG, message) => {
+                    self.message = message;
+                    continue;
+                }
+                (OPCODE_CLOSE, _) => {
+                    // Echo the client's code, as the close handshake asks.
+                    let code = payload.get(..2).map_or(CLOSE_NORMAL, |code| u16::from_be_bytes([code[0], code[1]]));
+                    debug!("Client closed the WebSocket connection with code {}", code);
+                    self.close(code, "");
+                    return Ok(None);
+                }
+                (OPCODE_TEXT | OPCODE_BINARY, None) => (opcode, payload),
+                (OPCODE_CONTINUATION, Some((opcode, mut message))) => {
+                    message.extend_from_slice(&payload);
+                    (opcode, message)
+                }
+                (OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION, _) => {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, protocol_error("unexpected continuation state")));
+                }
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, protocol_error("unknown opcode"))),
+            };
+            if !fin {
+                self.message = Some(message);
+                continue;
+            }
+            match message {
+                (OPCODE_BINARY, payload) => return Ok(Some(payload)),
+                _ => {
+                    let error = io::Error::new(ErrorKind::InvalidData, "text message on a binary WebSocket connection");
+                    return Err(self.fail(CLOSE_UNSUPPORTED_DATA, error));
+                }
+            }
+        }
+    }
+}
+
+impl Read for WebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read == self.frame.len() {
+            let Some(payload) = self.next_message()? else {
+                return Ok(0);
+            };
+            self.frame.clear();
+            self.frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            self.frame.extend_from_slice(&payload);
+            self.read = 0;
+        }
+        let read = cmp::min(buf.len(), self.frame.len() - self.read);
+        buf[..read].copy_from_slice(&self.frame[self.read..self.read + read]);
+        self.read += read;
+        Ok(read)
+    }
+}
+
+impl Write for WebSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        while let Some(header) = self.output.get(..HEADER_LEN) {
+            let header = u32::from_be_bytes(header.try_into().unwrap());
+            if header & (FLAG_COMPRESSED | FLAG_CHECKSUM) != 0 {
+                self.output.clear();
+                return Err(io::Error::new(ErrorKind::InvalidInput, "WebSocket messages are never compressed or checksummed"));
+            }
+            let end = HEADER_LEN + (header & LENGTH_MASK) as usize;
+            if self.output.len() < end {
+                break;
+            }
+            let payload: Vec<u8> = self.output.drain(..end).skip(HEADER_LEN).collect();
+            self.send(OPCODE_BINARY, &payload)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}